@@ -1,16 +1,23 @@
 pub mod run;
 
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use secrecy::SecretString;
 
-use crate::RateLimit;
+use crate::policy::Settings;
+
+/// Builds the action again from the current configuration, used on SIGHUP.
+pub type Reload = fn() -> anyhow::Result<Action>;
+
 #[derive(Debug)]
 pub enum Action {
     Run {
         dsn: SecretString,
         pool: u32,
         socket: PathBuf,
-        windows: Vec<RateLimit>,
+        idle_timeout: Duration,
+        settings: Settings,
+        /// Without it only the list files are read again on SIGHUP.
+        reload: Option<Reload>,
     },
 }
@@ -1,14 +1,134 @@
-use std::{path::Path, sync::Arc, time::Duration};
+use std::{
+    path::Path,
+    sync::{Arc, PoisonError, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Result;
 use futures::{SinkExt, StreamExt};
 use secrecy::ExposeSecret;
 use sqlx::any::AnyPoolOptions;
-use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    signal::unix::{Signal, SignalKind, signal},
+    time::timeout,
+};
 use tokio_util::codec::{Framed, LinesCodec};
 use tracing::{debug, error, info, warn};
 
-use crate::{RateLimit, cli::actions::Action, queries::Queries};
+use crate::{
+    RateLimit,
+    cli::actions::{Action, Reload},
+    duration::{format_rate, humanize},
+    policy::{PolicyRequest, ProtocolState, Scope, Settings, TemplateVars},
+    queries::{Consumption, Counter, DistinctWindow, Queries, RateLimitWindow},
+};
+
+const DUNNO: &str = "action=DUNNO";
+
+/// Settings shared by every connection, replaced as a whole on reload.
+type SharedSettings = Arc<RwLock<Arc<Settings>>>;
+
+fn current(settings: &SharedSettings) -> Arc<Settings> {
+    settings
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Options only applied on start, with their values to compare on reload.
+fn start_options(action: &Action) -> [(&'static str, String); 4] {
+    match action {
+        Action::Run {
+            dsn,
+            pool,
+            socket,
+            idle_timeout,
+            ..
+        } => [
+            ("socket", socket.display().to_string()),
+            ("dsn", dsn.expose_secret().to_string()),
+            ("pool", pool.to_string()),
+            ("idle-timeout", format!("{idle_timeout:?}")),
+        ],
+    }
+}
+
+/// New settings from `reload`, or the current ones with the list files read
+/// again when there is no way to reload the whole configuration.
+fn reload_settings(
+    current: &Settings,
+    reload: Option<Reload>,
+    started: &[(&'static str, String)],
+) -> Result<Settings> {
+    let Some(reload) = reload else {
+        return current.reload_lists();
+    };
+
+    let action = reload()?;
+
+    let ignored: Vec<&str> = start_options(&action)
+        .iter()
+        .zip(started)
+        .filter(|(new, old)| new != old)
+        .map(|((name, _), _)| *name)
+        .collect();
+    if !ignored.is_empty() {
+        warn!(
+            "Changes to {} only apply after a restart, keeping the current values",
+            ignored.join(", ")
+        );
+    }
+
+    let Action::Run { settings, .. } = action;
+
+    Ok(settings)
+}
+
+/// Reload the configuration on every SIGHUP. The settings are replaced as a
+/// whole while the socket and the database pool are kept, a failed reload
+/// keeps the current configuration.
+async fn reload_on_hangup(
+    mut hangup: Signal,
+    settings: SharedSettings,
+    reload: Option<Reload>,
+    started: [(&'static str, String); 4],
+) {
+    while hangup.recv().await.is_some() {
+        match reload_settings(&current(&settings), reload, &started) {
+            Ok(reloaded) => {
+                info!(
+                    "Reloaded configuration, windows {}, {} plans, {} allowlist and {} blocklist entries",
+                    describe(&reloaded.windows),
+                    reloaded.plans.len(),
+                    reloaded.allowlist.len(),
+                    reloaded.blocklist.len()
+                );
+
+                *settings.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(reloaded);
+            }
+            Err(e) => error!(
+                "Failed to reload the configuration, keeping the current one: {:#}",
+                e
+            ),
+        }
+    }
+}
+
+/// Windows as `LIMIT/RATE` for the logs, e.g. `100/1h, 1000/1d`.
+fn describe(windows: &[RateLimit]) -> String {
+    windows
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| i64::try_from(now.as_secs()).unwrap_or(i64::MAX))
+}
 
 fn redact_dsn(dsn: &str) -> String {
     let Some((scheme, rest)) = dsn.split_once("://") else {
@@ -33,12 +153,16 @@ fn redact_dsn(dsn: &str) -> String {
 /// # Errors
 /// Returns an error if the socket setup, database operations, or client handling fails.
 pub async fn handle(action: Action) -> Result<()> {
+    let started = start_options(&action);
+
     match action {
         Action::Run {
             dsn,
             pool,
             socket,
-            windows,
+            idle_timeout,
+            settings,
+            reload,
         } => {
             if Path::new(&socket).exists() {
                 std::fs::remove_file(&socket)?;
@@ -61,14 +185,22 @@ pub async fn handle(action: Action) -> Result<()> {
 
             let pool = AnyPoolOptions::new()
                 .max_connections(pool)
-                .idle_timeout(Duration::from_secs(300))
+                .idle_timeout(Duration::from_mins(5))
                 .connect(dsn_str)
                 .await?;
 
             debug!(?pool, "Pool created");
+            info!("Rate limiting with windows {}", describe(&settings.windows));
 
             let queries = Queries::new(pool);
-            let windows = Arc::new(windows);
+            let settings: SharedSettings = Arc::new(RwLock::new(Arc::new(settings)));
+
+            tokio::spawn(reload_on_hangup(
+                signal(SignalKind::hangup())?,
+                settings.clone(),
+                reload,
+                started,
+            ));
 
             // Start accepting connections
             loop {
@@ -76,8 +208,15 @@ pub async fn handle(action: Action) -> Result<()> {
                     Ok((stream, _)) => {
                         debug!("New client connected: {:#?}", stream.local_addr());
 
-                        // Spawn a new task to handle this client
-                        tokio::spawn(handle_client(stream, queries.clone(), windows.clone()));
+                        // Spawn a new task to handle this client, Postfix
+                        // gets no answer when it fails
+                        let client =
+                            handle_client(stream, queries.clone(), settings.clone(), idle_timeout);
+                        tokio::spawn(async move {
+                            if let Err(e) = client.await {
+                                error!("Failed to handle client, closing the connection: {:#}", e);
+                            }
+                        });
                     }
 
                     Err(e) => {
@@ -92,93 +231,425 @@ pub async fn handle(action: Action) -> Result<()> {
 async fn handle_client(
     stream: UnixStream,
     queries: Queries,
-    windows: Arc<Vec<RateLimit>>,
+    settings: SharedSettings,
+    idle_timeout: Duration,
 ) -> Result<()> {
     let mut framed = Framed::new(stream, LinesCodec::new());
-    let mut sasl_username: Option<String> = None;
+
+    // Postfix keeps the connection open and sends one request after another
+    loop {
+        let received_lines = match timeout(idle_timeout, read_request(&mut framed)).await {
+            Ok(Ok(Some(lines))) => lines,
+            Ok(Ok(None)) => {
+                debug!("Client closed the connection");
+                return Ok(());
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                debug!("Closing connection idle for {:?}", idle_timeout);
+                return Ok(());
+            }
+        };
+
+        let response = handle_request(&received_lines, &queries, &current(&settings)).await?;
+
+        send_policy_response(&mut framed, &response).await?;
+    }
+}
+
+/// Read the attribute lines of a single request, up to the terminating empty line.
+///
+/// Returns `None` if the client closed the connection before a complete request was received.
+async fn read_request(framed: &mut Framed<UnixStream, LinesCodec>) -> Result<Option<Vec<String>>> {
     let mut received_lines = Vec::new();
 
-    while let Some(Ok(line)) = framed.next().await {
-        let trimmed = line.trim().to_string();
+    while let Some(line) = framed.next().await {
+        let trimmed = line?.trim().to_string();
 
         if trimmed.is_empty() {
-            break;
+            if received_lines.is_empty() {
+                continue;
+            }
+
+            return Ok(Some(received_lines));
         }
 
-        received_lines.push(trimmed.clone());
+        received_lines.push(trimmed);
+    }
 
-        if let Some(name) = trimmed.strip_prefix("sasl_username=") {
-            sasl_username = Some(name.trim().to_string());
+    Ok(None)
+}
+
+async fn handle_request(
+    received_lines: &[String],
+    queries: &Queries,
+    settings: &Settings,
+) -> Result<String> {
+    let mut request = match PolicyRequest::parse(received_lines.iter().map(String::as_str)) {
+        Ok(request) => request,
+        Err(e) => {
+            error!(
+                "Malformed policy request: {}, Request:\n{}",
+                e,
+                received_lines.join("\n")
+            );
+
+            return Ok(DUNNO.to_string());
         }
+    };
+
+    settings.normalize.apply(&mut request);
+
+    // Blocked requests are refused even when they are also allowed
+    if let Some(entry) = settings.blocklist.find(&request) {
+        let user = request
+            .sasl_username
+            .as_deref()
+            .or(request.sender.as_deref())
+            .unwrap_or_default();
+        let response = settings
+            .block_action
+            .response(&settings.block_message.render(&TemplateVars {
+                user,
+                ..TemplateVars::default()
+            }));
+
+        warn!("Blocked by {}, {}: {:?}", entry, response, request);
+
+        return Ok(response);
     }
 
-    // Handle unauthenticated or empty SASL username (incoming mail)
-    let Some(username) = sasl_username else {
-        send_policy_response(&mut framed, "action=DUNNO").await?;
+    if let Some(entry) = settings.allowlist.find_authenticated(&request) {
+        debug!("Allowed by {}, not rate limited: {:?}", entry, request);
 
-        warn!("No SASL username in policy request. Likely incoming mail.");
+        return Ok(DUNNO.to_string());
+    }
 
-        return Ok(());
+    // Requests without the key attributes, with the default sasl_username key
+    // that is unauthenticated (incoming) mail, are only limited by their
+    // client network when configured
+    let (key_scope, key) = match settings.key.render(&request) {
+        Some(key) => (Scope::Key, key),
+        None => match Scope::Client.render(settings, &request) {
+            Some(client) if !settings.client_windows.is_empty() => (Scope::Client, client),
+            _ => {
+                debug!(
+                    "No {} in policy request. Likely incoming mail.",
+                    settings.key
+                );
+
+                return Ok(DUNNO.to_string());
+            }
+        },
     };
+    let username = key.as_str();
+
+    debug!("Rate limit key: {}, Request: {:?}", username, request);
 
-    if username.is_empty() {
-        send_policy_response(&mut framed, "action=DUNNO").await?;
+    // Users assigned to a plan are limited by its windows instead of the defaults
+    let settings = settings.for_request(&request);
+    let settings = settings.as_ref();
 
-        debug!("Empty SASL username in policy request. Skipping rate limit.");
+    let cost = settings.charge.cost(&request);
+    let size = request
+        .size
+        .map_or(0, |size| i64::try_from(size).unwrap_or(i64::MAX));
 
-        return Ok(());
+    if let Some(response) = check_distinct_clients(queries, settings, &request).await? {
+        return Ok(response);
     }
+    if let Some(response) = check_distinct_recipients(queries, settings, username, &request).await?
+    {
+        return Ok(response);
+    }
+
+    // Shared scopes are skipped when not configured or when the request
+    // lacks their attributes, e.g. a sender without a domain. Unauthenticated
+    // clients never charge the counters of authenticated users.
+    let shared = if key_scope == Scope::Key {
+        &Scope::ALL[..]
+    } else {
+        &[]
+    };
+    let scopes: Vec<(Scope, String)> = std::iter::once((key_scope, key.clone()))
+        .chain(
+            shared
+                .iter()
+                .copied()
+                .filter(|scope| *scope != Scope::Key && !settings.scope_windows(*scope).is_empty())
+                .filter_map(|scope| Some((scope, scope.render(settings, &request)?))),
+        )
+        .collect();
+
+    let counters: Vec<Counter<'_>> = scopes
+        .iter()
+        .map(|(scope, key)| Counter {
+            key,
+            windows: settings.scope_windows(*scope),
+        })
+        .collect();
+
+    let consumed = queries
+        .consume_all(&counters, cost, size, !settings.accepted_only)
+        .await?;
+    record_distinct_recipient(queries, settings, username, &request, &consumed).await?;
+
+    let windows: Vec<ScopedWindow<'_>> = scopes
+        .iter()
+        .zip(consumed)
+        .flat_map(|((scope, counter), consumption)| {
+            consumption
+                .windows
+                .into_iter()
+                .map(move |window| (*scope, counter.as_str(), window))
+        })
+        .collect();
+
+    Ok(respond(settings, username, &request, &windows, cost, size))
+}
+
+/// Over quota response when the recipient would exceed the distinct
+/// recipients `username` may mail per window, only checked in the RCPT
+/// state. The recipient is recorded once the request passed every quota,
+/// see [`record_distinct_recipient`].
+async fn check_distinct_recipients(
+    queries: &Queries,
+    settings: &Settings,
+    username: &str,
+    request: &PolicyRequest,
+) -> Result<Option<String>> {
+    let Some(recipient) = distinct_recipient(settings, request) else {
+        return Ok(None);
+    };
+
+    let now = unix_now();
+    let windows = queries
+        .count_recipients(username, &recipient, &settings.distinct_recipients, now)
+        .await?;
+
+    let Some(window) = windows
+        .iter()
+        .filter(|window| !window.allows())
+        .max_by_key(|window| window.rate)
+    else {
+        return Ok(None);
+    };
 
-    debug!(
-        "SASL username: {}, Request:\n{}",
+    let response = over_distinct(settings, username, window, now);
+
+    info!(
+        "User {} reached {} distinct recipients in the {} window, rejecting {}, try again in {}, {}",
         username,
-        received_lines.join("\n")
+        window.used,
+        format_rate(i64::from(window.rate)),
+        recipient,
+        humanize(window.reset_in(now)),
+        response
     );
 
-    match queries.reset_quotas_if_expired(&username).await {
-        Ok(true) => info!("Reset expired quotas for user {}", username),
-        Ok(false) => (),
-        Err(e) => error!("Error checking quota expiration: {:?}", e),
+    Ok(Some(response))
+}
+
+/// Record the recipient of a request accepted by every counter in
+/// `consumed`.
+async fn record_distinct_recipient(
+    queries: &Queries,
+    settings: &Settings,
+    username: &str,
+    request: &PolicyRequest,
+    consumed: &[Consumption],
+) -> Result<()> {
+    if let Some(recipient) = distinct_recipient(settings, request)
+        && consumed.iter().all(|consumption| consumption.allowed)
+    {
+        queries
+            .record_recipient(username, &recipient, unix_now())
+            .await?;
     }
 
-    let mut active_windows = queries.get_windows(&username).await?;
-    if active_windows.is_empty() {
-        info!("User {} not found, creating new user", username);
+    Ok(())
+}
+
+/// Recipient counted by the distinct recipients windows, `None` when there
+/// are none or outside the RCPT state.
+fn distinct_recipient(settings: &Settings, request: &PolicyRequest) -> Option<String> {
+    if settings.distinct_recipients.is_empty()
+        || request.protocol_state != Some(ProtocolState::Rcpt)
+    {
+        return None;
+    }
 
-        // User not found, create a new one
-        queries.create_user(&username, windows.as_ref()).await?;
+    request.recipient.as_deref().map(str::to_lowercase)
+}
 
-        send_policy_response(&mut framed, "action=DUNNO").await?;
-        return Ok(());
+/// Over quota response when the SASL user authenticated from more distinct
+/// client addresses per window than allowed, a hint of leaked credentials.
+/// With `distinct_clients_log_only` the alert is only logged.
+async fn check_distinct_clients(
+    queries: &Queries,
+    settings: &Settings,
+    request: &PolicyRequest,
+) -> Result<Option<String>> {
+    let (Some(username), Some(client_address)) =
+        (request.sasl_username.as_deref(), request.client_address)
+    else {
+        return Ok(None);
+    };
+    if settings.distinct_clients.is_empty() {
+        return Ok(None);
     }
 
-    if active_windows.len() < windows.len() {
-        if let Err(e) = queries.ensure_windows(&username, windows.as_ref()).await {
-            error!("Failed to add missing windows for {}: {:?}", username, e);
-        } else {
-            active_windows = queries.get_windows(&username).await?;
-        }
+    let now = unix_now();
+    let windows = queries
+        .track_client(
+            username,
+            &client_address.to_string(),
+            &settings.distinct_clients,
+            now,
+            settings.distinct_clients_log_only,
+        )
+        .await?;
+
+    let Some(window) = windows
+        .iter()
+        .filter(|window| !window.allows())
+        .max_by_key(|window| window.rate)
+    else {
+        return Ok(None);
+    };
+
+    warn!(
+        "ALERT possible credential sharing: SASL user {} authenticated from {} distinct client addresses in the {} window (limit {}), latest {}",
+        username,
+        window.used.saturating_add(1),
+        format_rate(i64::from(window.rate)),
+        window.quota,
+        client_address
+    );
+
+    if settings.distinct_clients_log_only {
+        return Ok(None);
+    }
+
+    Ok(Some(over_distinct(settings, username, window, now)))
+}
+
+/// Response for a value over the distinct values allowed in `window`.
+fn over_distinct(settings: &Settings, username: &str, window: &DistinctWindow, now: i64) -> String {
+    settings
+        .action
+        .response(&settings.message.render(&TemplateVars {
+            user: username,
+            window: window.rate,
+            quota: window.quota,
+            retry_after: window.reset_in(now),
+        }))
+}
+
+/// A window of the counter `.1` in scope `.0`.
+type ScopedWindow<'a> = (Scope, &'a str, RateLimitWindow);
+
+/// Where a window comes from in the logs, nothing for the key's own windows.
+fn counter_suffix(scope: Scope, counter: &str) -> String {
+    if scope == Scope::Key {
+        String::new()
+    } else {
+        format!(" of {counter}")
     }
+}
 
-    let allow = active_windows
+/// Response to a request checked against `windows`, as they were before it
+/// was charged.
+fn respond(
+    settings: &Settings,
+    username: &str,
+    request: &PolicyRequest,
+    windows: &[ScopedWindow<'_>],
+    cost: i32,
+    size: i64,
+) -> String {
+    // When several windows are exceeded the longest one decides the response,
+    // it is the one that keeps the user blocked the longest
+    let exceeded = windows
         .iter()
-        .all(|window| window.used < window.quota);
+        .filter(|(_, _, window)| !window.allows(cost, size))
+        .max_by_key(|(_, _, window)| window.rate);
+
+    if let Some((scope, counter, window)) = exceeded {
+        let retry_after = window.reset_in(unix_now());
+        let (action, message) = settings.over_quota(*scope, window.rate);
+        let response = action.response(&message.render(&TemplateVars {
+            user: username,
+            window: window.rate,
+            quota: window.quota,
+            retry_after,
+        }));
 
-    if allow {
-        info!("User {} is within quota", username);
+        info!(
+            "User {} exceeded the {} window{} ({}/{} used) for {} and {} bytes, try again in {}, {}",
+            username,
+            format_rate(i64::from(window.rate)),
+            counter_suffix(*scope, counter),
+            window.used,
+            window.quota,
+            cost,
+            size,
+            humanize(retry_after),
+            response
+        );
 
-        send_policy_response(&mut framed, "action=DUNNO").await?;
+        response
+    } else if let Some(((scope, counter, window), percent)) =
+        near_quota(settings, windows, cost, size)
+    {
+        warn!(
+            "User {} reached {}% of the {} window{} ({}/{} used), charging {} and {} bytes",
+            username,
+            percent,
+            format_rate(i64::from(window.rate)),
+            counter_suffix(*scope, counter),
+            window.used,
+            window.quota,
+            cost,
+            size
+        );
+
+        // Postfix does not support PREPEND in smtpd_end_of_data_restrictions
+        if request.protocol_state == Some(ProtocolState::EndOfMessage) {
+            return DUNNO.to_string();
+        }
+
+        format!(
+            "action=PREPEND X-RateLimit-Warning: {percent}% of the {} sending limit used",
+            format_rate(i64::from(window.rate))
+        )
     } else {
         info!(
-            "User {} is not within quota, sending limit exceeded, action=REJECT",
-            username
+            "User {} is within quota, charging {} and {} bytes",
+            username, cost, size
         );
-        send_policy_response(&mut framed, "action=REJECT sending limit exceeded").await?;
-    }
 
-    queries.update_quota(&username).await?;
+        DUNNO.to_string()
+    }
+}
 
-    Ok(())
+/// Longest window whose usage after this request crosses its warning threshold.
+fn near_quota<'a, 'b>(
+    settings: &Settings,
+    windows: &'a [ScopedWindow<'b>],
+    cost: i32,
+    size: i64,
+) -> Option<(&'a ScopedWindow<'b>, i64)> {
+    windows
+        .iter()
+        .filter_map(|scoped| {
+            let (scope, _, window) = scoped;
+            let warn = settings.window(*scope, window.rate)?.warn?;
+            let percent = window.usage_percent(cost, size);
+
+            (percent >= i64::from(warn)).then_some((scoped, percent))
+        })
+        .max_by_key(|((_, _, window), _)| window.rate)
 }
 
 /// Send a policy response to the client
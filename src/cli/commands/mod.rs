@@ -5,6 +5,12 @@ use clap::{
     builder::styling::{AnsiColor, Effects, Styles},
 };
 
+use crate::{
+    RateLimit,
+    duration::parse_rate,
+    policy::{Assignment, Entry, Key, Normalize, Plan, PolicyAction, Template},
+};
+
 pub mod built_info {
     #![allow(clippy::doc_markdown, clippy::needless_raw_string_hashes)]
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
@@ -25,6 +31,262 @@ fn validate_socket_path(path: &str) -> Result<PathBuf, String> {
     )
 }
 
+/// Parse a byte size with an optional K, M or G suffix (powers of 1024)
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((pos, 'k' | 'K')) => (&size[..pos], 1 << 10),
+        Some((pos, 'm' | 'M')) => (&size[..pos], 1 << 20),
+        Some((pos, 'g' | 'G')) => (&size[..pos], 1 << 30),
+        _ => (size, 1),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size: {size}"))
+}
+
+/// Parse a `LIMIT/RATE` window, e.g. `5000/86400` or `5000/1d`
+fn parse_window(window: &str) -> Result<RateLimit, String> {
+    let invalid = || format!("Invalid window: {window}, expected LIMIT/RATE");
+    let (limit, rate) = window.trim().split_once('/').ok_or_else(invalid)?;
+
+    let limit = limit
+        .trim()
+        .parse::<i32>()
+        .ok()
+        .filter(|limit| *limit >= 0)
+        .ok_or_else(invalid)?;
+
+    Ok(RateLimit::new(limit, parse_rate(rate)?))
+}
+
+/// Parse a `NAME=LIMIT/RATE[,LIMIT/RATE...]` plan, e.g. `business=1000/3600,10000/86400`
+fn parse_plan(plan: &str) -> Result<Plan, String> {
+    let (name, windows) = plan
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid plan: {plan}, expected NAME=LIMIT/RATE[,LIMIT/RATE...]"))?;
+
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("Invalid plan: {plan}, missing name"));
+    }
+
+    let windows = windows
+        .split(',')
+        .map(parse_window)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut rates: Vec<i32> = windows.iter().map(|window| window.rate).collect();
+    rates.sort_unstable();
+    rates.dedup();
+    if rates.len() != windows.len() {
+        return Err(format!("Invalid plan: {plan}, rates must be unique"));
+    }
+
+    Ok(Plan {
+        name: name.to_string(),
+        windows,
+        action: None,
+    })
+}
+
+/// Parse a `NAME=ACTION` plan action, e.g. `free=DEFER`
+fn parse_plan_action(plan_action: &str) -> Result<(String, PolicyAction), String> {
+    let (name, action) = plan_action
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid plan action: {plan_action}, expected NAME=ACTION"))?;
+
+    Ok((name.trim().to_string(), action.parse()?))
+}
+
+/// Arguments describing plans and the users assigned to them
+fn plan_args() -> [Arg; 3] {
+    [
+        Arg::new("plan")
+            .long("plan")
+            .help("Named windows used instead of --limit/--rate for the users assigned to them, e.g. business=1000/1h,10000/1d (repeatable)")
+            .value_name("NAME=LIMIT/RATE,...")
+            .action(ArgAction::Append)
+            .value_parser(parse_plan),
+        Arg::new("plan-action")
+            .long("plan-action")
+            .help("Action when a window of a plan is exceeded, defaults to --action (repeatable)")
+            .value_name("NAME=ACTION")
+            .action(ArgAction::Append)
+            .value_parser(parse_plan_action),
+        Arg::new("assign")
+            .long("assign")
+            .help("Assign users to a plan: user@example.com, @example.com for a domain or ~REGEX, the first match wins (repeatable)")
+            .value_name("PATTERN=PLAN")
+            .action(ArgAction::Append)
+            .value_parser(|assignment: &str| assignment.parse::<Assignment>()),
+    ]
+}
+
+/// Arguments listing users, domains and networks handled before any quota
+fn list_args() -> [Arg; 6] {
+    [
+        Arg::new("allow")
+            .long("allow")
+            .help("Never rate limit a user, @domain, ~REGEX or client network like 10.0.0.0/8 (repeatable)")
+            .value_name("ENTRY")
+            .action(ArgAction::Append)
+            .value_parser(|entry: &str| entry.parse::<Entry>()),
+        Arg::new("allowlist")
+            .long("allowlist")
+            .help("File with one --allow entry per line, reloaded on SIGHUP (repeatable)")
+            .value_name("FILE")
+            .action(ArgAction::Append)
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath),
+        Arg::new("block")
+            .long("block")
+            .help("Always answer a user, @domain, ~REGEX or client network with --block-action, checked before --allow (repeatable)")
+            .value_name("ENTRY")
+            .action(ArgAction::Append)
+            .value_parser(|entry: &str| entry.parse::<Entry>()),
+        Arg::new("blocklist")
+            .long("blocklist")
+            .help("File with one --block entry per line, reloaded on SIGHUP (repeatable)")
+            .value_name("FILE")
+            .action(ArgAction::Append)
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath),
+        Arg::new("block-action")
+            .long("block-action")
+            .help("Action for blocked requests")
+            .default_value("REJECT")
+            .value_parser(|action: &str| action.parse::<PolicyAction>()),
+        Arg::new("block-message")
+            .long("block-message")
+            .help("Reply text for blocked requests, supports {user}")
+            .default_value("sending blocked by the administrator")
+            .value_parser(|message: &str| message.parse::<Template>()),
+    ]
+}
+
+/// Arguments describing windows shared by many users
+fn shared_args() -> [Arg; 8] {
+    [
+        Arg::new("domain-window")
+            .long("domain-window")
+            .help("LIMIT/RATE window shared by every mailbox of a domain, e.g. 5000/1d (repeatable)")
+            .value_name("LIMIT/RATE")
+            .action(ArgAction::Append)
+            .value_parser(parse_window),
+        Arg::new("global-window")
+            .long("global-window")
+            .help("LIMIT/RATE window shared by every request, caps the whole server (repeatable)")
+            .value_name("LIMIT/RATE")
+            .action(ArgAction::Append)
+            .value_parser(parse_window),
+        Arg::new("global-action")
+            .long("global-action")
+            .help("Action when a global window is exceeded, defaults to --action")
+            .value_parser(|action: &str| action.parse::<PolicyAction>()),
+        Arg::new("recipient-domain-window")
+            .long("recipient-domain-window")
+            .help("LIMIT/RATE window per recipient domain, checked in the RCPT state (repeatable)")
+            .value_name("LIMIT/RATE")
+            .action(ArgAction::Append)
+            .value_parser(parse_window),
+        Arg::new("recipient-domain-action")
+            .long("recipient-domain-action")
+            .help("Action when a recipient domain window is exceeded")
+            .default_value("DEFER")
+            .value_parser(|action: &str| action.parse::<PolicyAction>()),
+        Arg::new("recipient-domain-per-key")
+            .long("recipient-domain-per-key")
+            .help("Count recipient domains separately for every key instead of for all senders")
+            .action(ArgAction::SetTrue),
+        Arg::new("client-window")
+            .long("client-window")
+            .help("LIMIT/RATE window per client /24 (IPv4) or /64 (IPv6) network for requests without SASL username (repeatable)")
+            .value_name("LIMIT/RATE")
+            .action(ArgAction::Append)
+            .value_parser(parse_window),
+        Arg::new("client-action")
+            .long("client-action")
+            .help("Action when a client window is exceeded, defaults to --action")
+            .value_parser(|action: &str| action.parse::<PolicyAction>()),
+    ]
+}
+
+/// Arguments limiting the distinct recipients and client addresses per user
+fn distinct_args() -> [Arg; 3] {
+    [
+        Arg::new("distinct-recipients")
+            .long("distinct-recipients")
+            .help("LIMIT/RATE maximum of distinct recipients per window, checked in the RCPT state (repeatable)")
+            .value_name("LIMIT/RATE")
+            .action(ArgAction::Append)
+            .value_parser(parse_window),
+        Arg::new("distinct-clients")
+            .long("distinct-clients")
+            .help("LIMIT/RATE maximum of distinct client addresses per SASL username and window (repeatable)")
+            .value_name("LIMIT/RATE")
+            .action(ArgAction::Append)
+            .value_parser(parse_window),
+        Arg::new("distinct-clients-log-only")
+            .long("distinct-clients-log-only")
+            .help("Only log SASL usernames over --distinct-clients instead of rejecting them")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+/// Arguments describing the rate windows and the response when they are exceeded
+fn window_args() -> [Arg; 8] {
+    [
+        Arg::new("limit")
+            .short('l')
+            .long("limit")
+            .help("Maximum allowed messages per rate window (repeatable, default: 10)")
+            .action(ArgAction::Append)
+            .value_parser(clap::value_parser!(u32)),
+        Arg::new("rate")
+            .short('r')
+            .long("rate")
+            .help("Length of each window in seconds or like 30m, 1h, 1d or 1w (repeatable, default: 1d)")
+            .action(ArgAction::Append)
+            .value_parser(parse_rate),
+        Arg::new("bytes")
+            .short('b')
+            .long("bytes")
+            .help("Maximum allowed bytes per rate window, accepts K/M/G suffixes (repeatable)")
+            .action(ArgAction::Append)
+            .value_parser(parse_size),
+        Arg::new("warn")
+            .long("warn")
+            .help("Add a warning header once this percent of the quota is used (once for all windows or once per window)")
+            .action(ArgAction::Append)
+            .value_parser(clap::value_parser!(u8).range(1..=100)),
+        Arg::new("charge")
+            .long("charge")
+            .help("Charge one unit per message or recipient_count units per request")
+            .default_value("messages")
+            .value_parser(["messages", "recipients"]),
+        Arg::new("accepted-only")
+            .long("accepted-only")
+            .help("Only count accepted requests against the quota, rejected ones are counted separately")
+            .action(ArgAction::SetTrue),
+        Arg::new("action")
+            .long("action")
+            .help("Action when over quota: REJECT, DEFER, DEFER_IF_PERMIT, HOLD, DISCARD or a reply code like \"450 4.7.1\" (once for all windows or once per window)")
+            .default_value("REJECT")
+            .action(ArgAction::Append)
+            .value_parser(|action: &str| action.parse::<PolicyAction>()),
+        Arg::new("message")
+            .long("message")
+            .help("Reply text when over quota, supports {user}, {window}, {quota}, {retry_after} and {retry_in} (once for all windows or once per window)")
+            .default_value("sending limit exceeded, try again in {retry_in}")
+            .action(ArgAction::Append)
+            .value_parser(|message: &str| message.parse::<Template>()),
+    ]
+}
+
 pub fn new() -> Command {
     let styles = Styles::styled()
         .header(AnsiColor::Yellow.on_default() | Effects::BOLD)
@@ -38,6 +300,15 @@ pub fn new() -> Command {
         .long_version(built_info::GIT_COMMIT_HASH)
         .color(ColorChoice::Auto)
         .styles(styles)
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .help("TOML file with the options not given on the command line")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_hint(ValueHint::FilePath),
+        )
         .arg(
             Arg::new("socket")
                 .short('s')
@@ -53,7 +324,15 @@ pub fn new() -> Command {
                 .long("dsn")
                 .help("Database connection string")
                 .env("DSN")
-                .required(true),
+                .required_unless_present_any(["config", "dsn-file"]),
+        )
+        .arg(
+            Arg::new("dsn-file")
+                .long("dsn-file")
+                .help("File with the database connection string, instead of --dsn")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_hint(ValueHint::FilePath),
         )
         .arg(
             Arg::new("pool")
@@ -63,21 +342,43 @@ pub fn new() -> Command {
                 .value_parser(clap::value_parser!(u32)),
         )
         .arg(
-            Arg::new("limit")
-                .short('l')
-                .long("limit")
-                .help("Maximum allowed messages per rate window (repeatable, default: 10)")
-                .action(ArgAction::Append)
-                .value_parser(clap::value_parser!(u32)),
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .help("Seconds to keep an idle Postfix connection open")
+                .default_value("600")
+                .value_parser(clap::value_parser!(u64)),
         )
         .arg(
-            Arg::new("rate")
-                .short('r')
-                .long("rate")
-                .help("rate in seconds for each window (repeatable, default: 86400)")
-                .action(ArgAction::Append)
-                .value_parser(clap::value_parser!(u32)),
+            Arg::new("key")
+                .long("key")
+                .help("Request attributes to rate limit by, joined with +: sasl_username, sender, sender_domain, client_address or client_network (/24 or /64)")
+                .default_value("sasl_username")
+                .value_parser(|key: &str| key.parse::<Key>()),
+        )
+        .arg(
+            Arg::new("normalize")
+                .long("normalize")
+                .help("Normalize the SASL username and sender, comma separated: trim, lowercase, strip-plus, idn")
+                .value_name("STEPS")
+                .value_parser(|steps: &str| steps.parse::<Normalize>()),
+        )
+        .arg(
+            Arg::new("default-domain")
+                .long("default-domain")
+                .help("Domain appended to SASL usernames without one")
+                .value_name("DOMAIN"),
         )
+        .next_help_heading("Rate windows")
+        .args(window_args())
+        .next_help_heading("Lists")
+        .args(list_args())
+        .next_help_heading("Plans")
+        .args(plan_args())
+        .next_help_heading("Distinct values")
+        .args(distinct_args())
+        .next_help_heading("Shared windows")
+        .args(shared_args())
+        .next_help_heading(None)
         .arg(
             Arg::new("verbose")
                 .short('v')
@@ -173,8 +474,17 @@ mod tests {
 
     #[test]
     fn test_rate() -> Result<()> {
-        let matches =
-            new().try_get_matches_from(["bin", "-r", "3600", "--dsn", "", "-s", "/tmp/a.sock"]);
+        let matches = new().try_get_matches_from([
+            "bin",
+            "-r",
+            "3600",
+            "-r",
+            "1d",
+            "--dsn",
+            "",
+            "-s",
+            "/tmp/a.sock",
+        ]);
 
         let m = matches?;
 
@@ -187,11 +497,152 @@ mod tests {
 
         assert_eq!(m.get_one::<String>("dsn").map(String::as_str), Some(""));
 
-        let rates: Vec<u32> = m
+        let rates: Vec<i32> = m
             .get_many("rate")
             .map(|values| values.copied().collect())
             .unwrap_or_default();
-        assert_eq!(rates, vec![3600]);
+        assert_eq!(rates, vec![3600, 86400]);
+
+        for rate in ["0", "8640x", "400d"] {
+            assert!(
+                new()
+                    .try_get_matches_from(["bin", "-r", rate, "--dsn", ""])
+                    .is_err()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytes() -> Result<()> {
+        let matches = new().try_get_matches_from([
+            "bin", "-b", "1024", "-b", "10M", "--bytes", "2g", "--dsn", "",
+        ]);
+
+        let m = matches?;
+
+        let bytes: Vec<u64> = m
+            .get_many("bytes")
+            .map(|values| values.copied().collect())
+            .unwrap_or_default();
+        assert_eq!(bytes, vec![1024, 10 * 1024 * 1024, 2 * 1024 * 1024 * 1024]);
+
+        assert!(
+            new()
+                .try_get_matches_from(["bin", "-b", "10X", "--dsn", ""])
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_action_and_message() -> Result<()> {
+        let matches = new().try_get_matches_from([
+            "bin",
+            "--action",
+            "450 4.7.1",
+            "--message",
+            "limit of {quota} reached",
+            "--dsn",
+            "",
+        ]);
+
+        let m = matches?;
+
+        assert_eq!(
+            m.get_one::<PolicyAction>("action"),
+            Some(&PolicyAction::Reply {
+                code: 450,
+                status: Some("4.7.1".to_string())
+            })
+        );
+        assert_eq!(
+            m.get_one::<Template>("message").map(ToString::to_string),
+            Some("limit of {quota} reached".to_string())
+        );
+
+        assert!(
+            new()
+                .try_get_matches_from(["bin", "--action", "OK", "--dsn", ""])
+                .is_err()
+        );
+        assert!(
+            new()
+                .try_get_matches_from(["bin", "--message", "{bogus}", "--dsn", ""])
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_domain_window() -> Result<()> {
+        let m = new().try_get_matches_from([
+            "bin",
+            "--domain-window",
+            "500/3600",
+            "--domain-window",
+            "5000/1d",
+            "--dsn",
+            "",
+        ])?;
+
+        let windows: Vec<RateLimit> = m
+            .get_many("domain-window")
+            .map(|values| values.cloned().collect())
+            .unwrap_or_default();
+        assert_eq!(
+            windows,
+            vec![RateLimit::new(500, 3600), RateLimit::new(5000, 86400)]
+        );
+
+        for invalid in ["5000", "5000/0", "-1/60", "a/b", "10/1y"] {
+            assert!(
+                new()
+                    .try_get_matches_from(["bin", "--domain-window", invalid, "--dsn", ""])
+                    .is_err()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan() -> Result<()> {
+        let m = new().try_get_matches_from([
+            "bin",
+            "--plan",
+            "business=1000/3600, 10000/86400",
+            "--plan-action",
+            "business=DEFER",
+            "--assign",
+            "@example.com=business",
+            "--dsn",
+            "",
+        ])?;
+
+        assert_eq!(
+            m.get_one::<Plan>("plan"),
+            Some(&Plan {
+                name: "business".to_string(),
+                windows: vec![RateLimit::new(1000, 3600), RateLimit::new(10000, 86400)],
+                action: None,
+            })
+        );
+        assert_eq!(
+            m.get_one::<(String, PolicyAction)>("plan-action"),
+            Some(&("business".to_string(), PolicyAction::Defer))
+        );
+
+        for invalid in ["business", "=10/60", "business=10/60,20/1m"] {
+            assert!(
+                new()
+                    .try_get_matches_from(["bin", "--plan", invalid, "--dsn", ""])
+                    .is_err()
+            );
+        }
 
         Ok(())
     }
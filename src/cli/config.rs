@@ -0,0 +1,341 @@
+use std::{ffi::OsString, fs, path::Path};
+
+use anyhow::{Context, Result, anyhow};
+use clap::{Arg, ArgAction, ArgMatches, Command, parser::ValueSource};
+use toml::{Table, Value};
+
+/// Options of a `[[window]]` table, in the order they are passed on.
+const WINDOW_KEYS: [&str; 6] = ["limit", "rate", "bytes", "warn", "action", "message"];
+
+/// Options set together, the file can not set part of a group given on the
+/// command line.
+const GROUPS: [&[&str]; 2] = [&["limit", "rate", "bytes", "warn"], &["dsn", "dsn-file"]];
+
+/// Options that only make sense on the command line.
+const CLI_ONLY: [&str; 3] = ["config", "help", "version"];
+
+/// Whether `id`, or another option of its group, was given on the command
+/// line or in the environment.
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    let single = [id];
+    let group = GROUPS
+        .iter()
+        .find(|group| group.contains(&id))
+        .map_or(&single[..], |group| *group);
+
+    group.iter().any(|id| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    })
+}
+
+/// A TOML value as a command line value.
+fn scalar(key: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Float(value) => Ok(value.to_string()),
+        Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(anyhow!("{key} must be a string, number or boolean")),
+    }
+}
+
+/// Command line arguments for the option `arg` set to `value`.
+fn option_args(arg: &Arg, key: &str, value: &Value) -> Result<Vec<OsString>> {
+    let long = arg
+        .get_long()
+        .ok_or_else(|| anyhow!("unknown option: {key}"))?;
+    let flag = || OsString::from(format!("--{long}"));
+
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue, Value::Boolean(set)) => Ok(if *set { vec![flag()] } else { vec![] }),
+        (ArgAction::SetTrue, _) => Err(anyhow!("{key} must be a boolean")),
+        (ArgAction::Count, Value::Integer(count)) => {
+            Ok(vec![flag(); usize::try_from(*count).unwrap_or_default()])
+        }
+        (ArgAction::Count, _) => Err(anyhow!("{key} must be a number")),
+        (_, Value::Array(values)) => values
+            .iter()
+            .map(|value| Ok(format!("--{long}={}", scalar(key, value)?).into()))
+            .collect(),
+        (_, value) => Ok(vec![format!("--{long}={}", scalar(key, value)?).into()]),
+    }
+}
+
+/// The `[[window]]` tables, or `[window.NAME]` tables when the windows are
+/// named.
+fn window_tables(windows: &Value) -> Result<Vec<&Table>> {
+    let windows: Vec<&Value> = match windows {
+        Value::Array(windows) => windows.iter().collect(),
+        Value::Table(windows) => windows.values().collect(),
+        _ => return Err(anyhow!("window must be an array of tables")),
+    };
+
+    windows
+        .into_iter()
+        .map(|window| {
+            let window = window
+                .as_table()
+                .ok_or_else(|| anyhow!("window must be a table"))?;
+
+            match window
+                .keys()
+                .find(|key| !WINDOW_KEYS.contains(&key.as_str()))
+            {
+                Some(key) => Err(anyhow!("unknown window option: {key}")),
+                None => Ok(window),
+            }
+        })
+        .collect()
+}
+
+/// Arguments for the window tables. The over quota action and message fall
+/// back to the top-level ones for the windows without their own.
+fn window_args(
+    cmd: &Command,
+    windows: &[&Table],
+    file: &Table,
+    matches: &ArgMatches,
+) -> Result<Vec<OsString>> {
+    let mut args = Vec::new();
+
+    for key in WINDOW_KEYS {
+        let set = windows
+            .iter()
+            .filter(|window| window.contains_key(key))
+            .count();
+        if set == 0 || given(matches, key) {
+            continue;
+        }
+
+        let arg = cmd
+            .get_arguments()
+            .find(|arg| arg.get_id() == key)
+            .ok_or_else(|| anyhow!("unknown option: {key}"))?;
+
+        let default = match key {
+            "action" | "message" => file.get(key).cloned().or_else(|| {
+                arg.get_default_values()
+                    .first()
+                    .map(|value| Value::String(value.to_string_lossy().into_owned()))
+            }),
+            _ => None,
+        };
+
+        for window in windows {
+            let value = window
+                .get(key)
+                .or(default.as_ref())
+                .ok_or_else(|| anyhow!("{key} must be set on every window or none"))?;
+            args.extend(option_args(arg, key, value)?);
+        }
+    }
+
+    Ok(args)
+}
+
+/// Command line arguments for the options in the TOML file `path` that were
+/// not given on the command line or in the environment. Every option can be
+/// set under its long name with `_` instead of `-`, windows also as
+/// `[[window]]` tables.
+///
+/// # Errors
+/// Returns an error if the file can not be read or parsed, or has an unknown
+/// option or a value of the wrong type.
+pub fn file_args(cmd: &Command, path: &Path, matches: &ArgMatches) -> Result<Vec<OsString>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let file: Table =
+        toml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))?;
+
+    let windows = file
+        .get("window")
+        .map(window_tables)
+        .transpose()?
+        .unwrap_or_default();
+    if !windows.is_empty() && (file.contains_key("limit") || file.contains_key("rate")) {
+        return Err(anyhow!("use either window tables or limit and rate"));
+    }
+
+    // Windows given on the command line replace the ones of the file
+    let windows = if given(matches, "limit") {
+        Vec::new()
+    } else {
+        windows
+    };
+
+    let mut args = window_args(cmd, &windows, &file, matches)?;
+    for (key, value) in file.iter().filter(|(key, _)| *key != "window") {
+        let id = key.replace('_', "-");
+        let arg = cmd
+            .get_arguments()
+            .find(|arg| arg.get_id() == id.as_str() && !CLI_ONLY.contains(&id.as_str()))
+            .ok_or_else(|| anyhow!("unknown option in {}: {key}", path.display()))?;
+
+        // Already passed on with the windows that have their own
+        let per_window = windows.iter().any(|window| window.contains_key(key));
+
+        if !given(matches, &id) && !per_window {
+            args.extend(option_args(arg, key, value)?);
+        }
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{RateLimit, cli::commands::new, policy::PolicyAction};
+
+    fn write_config(name: &str, content: &str) -> Result<PathBuf> {
+        let path = std::env::temp_dir().join(format!(
+            "policyd-rate-limit-{name}-{}.toml",
+            std::process::id()
+        ));
+        fs::write(&path, content)?;
+
+        Ok(path)
+    }
+
+    /// Parse `cli` with the options of the config file `content`.
+    fn parse(name: &str, content: &str, cli: &[&str]) -> Result<ArgMatches> {
+        let path = write_config(name, content)?;
+        let config = path.to_string_lossy().into_owned();
+
+        let mut cli_args: Vec<OsString> = vec!["bin".into(), "--config".into(), config.into()];
+        cli_args.extend(cli.iter().map(OsString::from));
+
+        let from_file = new()
+            .try_get_matches_from(&cli_args)
+            .map_err(anyhow::Error::from)
+            .and_then(|matches| file_args(&new(), &path, &matches));
+        let _ = fs::remove_file(&path);
+
+        cli_args.extend(from_file?);
+        Ok(new().try_get_matches_from(cli_args)?)
+    }
+
+    #[test]
+    fn test_file_args() -> Result<()> {
+        let m = parse(
+            "file",
+            r#"
+                dsn = "sqlite::memory:"
+                pool = 10
+                verbose = 2
+                accepted_only = true
+                action = "DEFER"
+                allow = ["10.0.0.0/8", "monitor@example.com"]
+
+                [[window]]
+                limit = 100
+                rate = "1h"
+                action = "REJECT"
+
+                [[window]]
+                limit = 1000
+                rate = 86400
+            "#,
+            &[],
+        )?;
+
+        assert_eq!(
+            m.get_one::<String>("dsn").map(String::as_str),
+            Some("sqlite::memory:")
+        );
+        assert_eq!(m.get_one::<u32>("pool").copied(), Some(10));
+        assert_eq!(m.get_count("verbose"), 2);
+        assert!(m.get_flag("accepted-only"));
+        assert_eq!(
+            m.get_many::<crate::policy::Entry>("allow")
+                .map(Iterator::count),
+            Some(2)
+        );
+
+        let limits: Vec<u32> = m
+            .get_many("limit")
+            .map(|v| v.copied().collect())
+            .unwrap_or_default();
+        assert_eq!(limits, vec![100, 1000]);
+        let rates: Vec<i32> = m
+            .get_many("rate")
+            .map(|v| v.copied().collect())
+            .unwrap_or_default();
+        assert_eq!(rates, vec![3600, 86400]);
+        // The second window falls back to the top-level action
+        let actions: Vec<PolicyAction> = m
+            .get_many("action")
+            .map(|v| v.cloned().collect())
+            .unwrap_or_default();
+        assert_eq!(actions, vec![PolicyAction::Reject, PolicyAction::Defer]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cli_overrides_file() -> Result<()> {
+        let m = parse(
+            "override",
+            r#"
+                dsn = "sqlite::memory:"
+                pool = 10
+                domain_window = ["500/3600"]
+
+                [window.hourly]
+                limit = 100
+                rate = 3600
+                bytes = "50M"
+            "#,
+            &[
+                "--pool",
+                "20",
+                "-l",
+                "7",
+                "-r",
+                "60",
+                "--dsn-file",
+                "/etc/dsn",
+            ],
+        )?;
+
+        assert_eq!(m.get_one::<u32>("pool").copied(), Some(20));
+        assert_eq!(m.get_one::<String>("dsn"), None);
+        let limits: Vec<u32> = m
+            .get_many("limit")
+            .map(|v| v.copied().collect())
+            .unwrap_or_default();
+        assert_eq!(limits, vec![7]);
+        assert_eq!(m.get_many::<u64>("bytes").map(Iterator::count), None);
+        assert_eq!(
+            m.get_many::<RateLimit>("domain-window")
+                .map(|v| v.cloned().collect::<Vec<_>>()),
+            Some(vec![RateLimit::new(500, 3600)])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        for content in [
+            "bogus = 1",
+            "config = \"/etc/other.toml\"",
+            "pool = \"many\"",
+            "accepted_only = \"yes\"",
+            "[[window]]\nlimit = 1\nrate = 60\nbytes = 10\n[[window]]\nlimit = 2\nrate = 120",
+            "[[window]]\nlimit = 1\nrate = 60\nlimt = 2",
+            "limit = [1]\nrate = [60]\n[[window]]\nlimit = 1\nrate = 60",
+            "not toml",
+        ] {
+            assert!(
+                parse("invalid", &format!("dsn = \"\"\n{content}"), &[]).is_err(),
+                "{content}"
+            );
+        }
+    }
+}
@@ -1,26 +1,79 @@
 use std::collections::HashSet;
-use std::path::PathBuf;
+use std::{fs, path::PathBuf, time::Duration};
 
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow};
 use secrecy::SecretString;
 
 use crate::RateLimit;
 use crate::cli::actions::Action;
+use crate::policy::{
+    AddressList, Assignment, Charge, Entry, Key, Normalize, Plan, PolicyAction, Settings, Template,
+};
+
+/// Spread per-window values over `windows` windows: none leaves every window
+/// unset, a single value applies to all of them, otherwise one value per window
+/// is required.
+fn per_window<T: Clone>(name: &str, values: Vec<T>, windows: usize) -> Result<Vec<Option<T>>> {
+    match values.len() {
+        0 => Ok(vec![None; windows]),
+        1 => Ok(vec![values.into_iter().next(); windows]),
+        n if n == windows => Ok(values.into_iter().map(Some).collect()),
+        _ => Err(anyhow!(
+            "{name} must be given once or once per limit/rate pair"
+        )),
+    }
+}
 
-/// Build an action from parsed CLI arguments.
-///
-/// # Errors
-/// Returns an error if required arguments are missing or cannot be converted.
-pub fn handler(matches: &clap::ArgMatches) -> Result<Action> {
-    let socket = matches
-        .get_one::<PathBuf>("socket")
-        .cloned()
-        .ok_or_else(|| anyhow!("socket required"))?;
+/// A single value is the default for every window, otherwise one value per
+/// window is required.
+fn default_or_per_window<T: Clone>(
+    name: &str,
+    values: Vec<T>,
+    windows: usize,
+) -> Result<(Option<T>, Vec<Option<T>>)> {
+    if values.len() == 1 {
+        Ok((values.into_iter().next(), vec![None; windows]))
+    } else {
+        Ok((None, per_window(name, values, windows)?))
+    }
+}
+
+/// Windows given as `LIMIT/RATE`, all with the same over quota `action`.
+/// Like the key's own they are stored with one row per rate.
+fn shared_windows(
+    matches: &clap::ArgMatches,
+    name: &str,
+    action: Option<&PolicyAction>,
+) -> Result<Vec<RateLimit>> {
+    let windows: Vec<RateLimit> =
+        matches
+            .get_many::<RateLimit>(name)
+            .map_or_else(Vec::new, |values| {
+                values
+                    .map(|window| RateLimit {
+                        action: action.cloned(),
+                        ..window.clone()
+                    })
+                    .collect()
+            });
+
+    let unique_rates: HashSet<i32> = windows.iter().map(|window| window.rate).collect();
+    if unique_rates.len() != windows.len() {
+        return Err(anyhow!("{name} rates must be unique"));
+    }
 
+    Ok(windows)
+}
+
+/// Windows of the key from the limit/rate pairs, along with the default over
+/// quota action and message when they are given once for all windows.
+fn key_windows(
+    matches: &clap::ArgMatches,
+) -> Result<(Vec<RateLimit>, Option<PolicyAction>, Option<Template>)> {
     let limits: Vec<u32> = matches
         .get_many("limit")
         .map_or_else(|| vec![10], |values| values.copied().collect());
-    let rates: Vec<u32> = matches
+    let rates: Vec<i32> = matches
         .get_many("rate")
         .map_or_else(|| vec![86400], |values| values.copied().collect());
 
@@ -28,32 +81,220 @@ pub fn handler(matches: &clap::ArgMatches) -> Result<Action> {
         return Err(anyhow!("limit/rate pairs must match"));
     }
 
-    let unique_rates: HashSet<u32> = rates.iter().copied().collect();
+    let unique_rates: HashSet<i32> = rates.iter().copied().collect();
     if unique_rates.len() != rates.len() {
         return Err(anyhow!("rate values must be unique"));
     }
 
+    let bytes: Vec<u64> = matches
+        .get_many("bytes")
+        .map_or_else(Vec::new, |values| values.copied().collect());
+    let bytes = per_window("bytes", bytes, rates.len())?;
+
+    let warns: Vec<u8> = matches
+        .get_many("warn")
+        .map_or_else(Vec::new, |values| values.copied().collect());
+    let warns = per_window("warn", warns, rates.len())?;
+
+    let actions: Vec<PolicyAction> = matches
+        .get_many("action")
+        .map_or_else(Vec::new, |values| values.cloned().collect());
+    let (action, actions) = default_or_per_window("action", actions, rates.len())?;
+
+    let messages: Vec<Template> = matches
+        .get_many("message")
+        .map_or_else(Vec::new, |values| values.cloned().collect());
+    let (message, messages) = default_or_per_window("message", messages, rates.len())?;
+
     let windows = limits
         .into_iter()
         .zip(rates)
-        .map(|(limit, rate)| {
+        .zip(bytes.into_iter().zip(warns))
+        .zip(actions.into_iter().zip(messages))
+        .map(|(((limit, rate), (bytes, warn)), (action, message))| {
             Ok(RateLimit {
                 limit: i32::try_from(limit).map_err(|_| anyhow!("limit must fit in i32"))?,
-                rate: i32::try_from(rate).map_err(|_| anyhow!("rate must fit in i32"))?,
+                rate,
+                bytes: bytes
+                    .map(i64::try_from)
+                    .transpose()
+                    .map_err(|_| anyhow!("bytes must fit in i64"))?,
+                action,
+                message,
+                warn,
             })
         })
         .collect::<Result<Vec<_>>>()?;
 
+    Ok((windows, action, message))
+}
+
+/// Plans with their actions, and the users assigned to them.
+fn plans(matches: &clap::ArgMatches) -> Result<(Vec<Plan>, Vec<Assignment>)> {
+    let mut plans: Vec<Plan> = matches
+        .get_many::<Plan>("plan")
+        .map_or_else(Vec::new, |values| values.cloned().collect());
+
+    let unique_names: HashSet<&str> = plans.iter().map(|plan| plan.name.as_str()).collect();
+    if unique_names.len() != plans.len() {
+        return Err(anyhow!("plan names must be unique"));
+    }
+
+    if let Some(actions) = matches.get_many::<(String, PolicyAction)>("plan-action") {
+        for (name, action) in actions {
+            let plan = plans
+                .iter_mut()
+                .find(|plan| plan.name == *name)
+                .ok_or_else(|| anyhow!("plan-action for unknown plan: {name}"))?;
+            plan.action = Some(action.clone());
+        }
+    }
+
+    let assignments: Vec<Assignment> = matches
+        .get_many::<Assignment>("assign")
+        .map_or_else(Vec::new, |values| values.cloned().collect());
+
+    if let Some(assignment) = assignments
+        .iter()
+        .find(|assignment| !plans.iter().any(|plan| plan.name == assignment.plan))
+    {
+        return Err(anyhow!(
+            "{} is assigned to unknown plan: {}",
+            assignment.matcher,
+            assignment.plan
+        ));
+    }
+
+    Ok((plans, assignments))
+}
+
+/// List of the `name` entries and the entries of the `file` files.
+fn address_list(matches: &clap::ArgMatches, name: &str, file: &str) -> Result<AddressList> {
+    AddressList::new(
+        matches
+            .get_many::<Entry>(name)
+            .map_or_else(Vec::new, |values| values.cloned().collect()),
+        matches
+            .get_many::<PathBuf>(file)
+            .map_or_else(Vec::new, |values| values.cloned().collect()),
+    )
+}
+
+/// Build the rate limiting settings from parsed CLI arguments.
+///
+/// # Errors
+/// Returns an error if the windows are inconsistent or cannot be converted.
+pub fn settings(matches: &clap::ArgMatches) -> Result<Settings> {
+    let (windows, action, message) = key_windows(matches)?;
+
+    let charge = matches
+        .get_one::<String>("charge")
+        .map(|charge| charge.parse::<Charge>())
+        .transpose()
+        .map_err(|e| anyhow!(e))?
+        .unwrap_or_default();
+
+    let distinct_recipients = shared_windows(matches, "distinct-recipients", None)?;
+    let distinct_clients = shared_windows(matches, "distinct-clients", None)?;
+    let domain_windows = shared_windows(matches, "domain-window", None)?;
+
+    let global_windows = shared_windows(
+        matches,
+        "global-window",
+        matches.get_one::<PolicyAction>("global-action"),
+    )?;
+    let recipient_domain_windows = shared_windows(
+        matches,
+        "recipient-domain-window",
+        matches.get_one::<PolicyAction>("recipient-domain-action"),
+    )?;
+    let client_windows = shared_windows(
+        matches,
+        "client-window",
+        matches.get_one::<PolicyAction>("client-action"),
+    )?;
+
+    let key = matches.get_one::<Key>("key").cloned().unwrap_or_default();
+    let (plans, assignments) = plans(matches)?;
+    if !assignments.is_empty() && !key.is_per_user() {
+        return Err(anyhow!(
+            "--assign needs a --key with sasl_username or sender, not {key}"
+        ));
+    }
+
+    let allowlist = address_list(matches, "allow", "allowlist")?;
+    let blocklist = address_list(matches, "block", "blocklist")?;
+
+    Ok(Settings {
+        key,
+        normalize: Normalize {
+            default_domain: matches.get_one::<String>("default-domain").cloned(),
+            ..matches
+                .get_one::<Normalize>("normalize")
+                .cloned()
+                .unwrap_or_default()
+        },
+        windows,
+        distinct_recipients,
+        distinct_clients,
+        distinct_clients_log_only: matches.get_flag("distinct-clients-log-only"),
+        domain_windows,
+        global_windows,
+        recipient_domain_windows,
+        recipient_domain_per_key: matches.get_flag("recipient-domain-per-key"),
+        client_windows,
+        charge,
+        action: action.unwrap_or_default(),
+        message: message.unwrap_or_default(),
+        accepted_only: matches.get_flag("accepted-only"),
+        plans,
+        assignments,
+        allowlist,
+        blocklist,
+        block_action: matches
+            .get_one::<PolicyAction>("block-action")
+            .cloned()
+            .unwrap_or_default(),
+        block_message: matches
+            .get_one::<Template>("block-message")
+            .cloned()
+            .unwrap_or_default(),
+    })
+}
+
+/// Build an action from parsed CLI arguments.
+///
+/// # Errors
+/// Returns an error if required arguments are missing or cannot be converted.
+pub fn handler(matches: &clap::ArgMatches) -> Result<Action> {
+    let socket = matches
+        .get_one::<PathBuf>("socket")
+        .cloned()
+        .ok_or_else(|| anyhow!("socket required"))?;
+
+    let dsn = match matches.get_one::<PathBuf>("dsn-file") {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .trim()
+            .to_string(),
+        None => matches
+            .get_one::<String>("dsn")
+            .cloned()
+            .ok_or_else(|| anyhow!("dsn or dsn-file required"))?,
+    };
+
     Ok(Action::Run {
         socket,
-        dsn: SecretString::from(
+        dsn: SecretString::from(dsn),
+        pool: matches.get_one::<u32>("pool").copied().unwrap_or(5),
+        idle_timeout: Duration::from_secs(
             matches
-                .get_one::<String>("dsn")
-                .cloned()
-                .unwrap_or_default(),
+                .get_one::<u64>("idle-timeout")
+                .copied()
+                .unwrap_or(600),
         ),
-        pool: matches.get_one::<u32>("pool").copied().unwrap_or(5),
-        windows,
+        settings: settings(matches)?,
+        reload: None,
     })
 }
 
@@ -64,7 +305,7 @@ mod tests {
     use secrecy::ExposeSecret;
 
     use super::*;
-    use crate::cli::commands::new;
+    use crate::{cli::commands::new, policy::normalize::Step};
 
     #[test]
     fn test_handler() -> Result<()> {
@@ -88,18 +329,21 @@ mod tests {
                 socket,
                 dsn,
                 pool,
-                windows,
+                idle_timeout,
+                settings,
+                reload,
             } => {
                 assert_eq!(socket, Path::new("/tmp/a.sock"));
                 assert_eq!(dsn.expose_secret(), "");
-                assert_eq!(
-                    windows,
-                    vec![RateLimit {
-                        limit: 10,
-                        rate: 86400
-                    }]
-                );
+                assert_eq!(settings.key, Key::default());
+                assert_eq!(settings.windows, vec![RateLimit::new(10, 86400)]);
+                assert_eq!(settings.charge, Charge::Messages);
+                assert_eq!(settings.action, PolicyAction::Reject);
+                assert_eq!(settings.message, Template::default());
+                assert!(!settings.accepted_only);
                 assert_eq!(pool, 5);
+                assert_eq!(idle_timeout, Duration::from_mins(10));
+                assert!(reload.is_none());
             }
         }
 
@@ -128,19 +372,10 @@ mod tests {
         let action = handler(&m)?;
 
         match action {
-            Action::Run { windows, .. } => {
+            Action::Run { settings, .. } => {
                 assert_eq!(
-                    windows,
-                    vec![
-                        RateLimit {
-                            limit: 7,
-                            rate: 3600,
-                        },
-                        RateLimit {
-                            limit: 100,
-                            rate: 86400,
-                        },
-                    ]
+                    settings.windows,
+                    vec![RateLimit::new(7, 3600), RateLimit::new(100, 86400),]
                 );
             }
         }
@@ -226,4 +461,554 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_charge_recipients() -> Result<()> {
+        let matches = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "-s",
+            "/tmp/a.sock",
+            "--charge",
+            "recipients",
+        ]);
+
+        let m = matches?;
+        let action = handler(&m)?;
+
+        match action {
+            Action::Run { settings, .. } => {
+                assert_eq!(settings.charge, Charge::Recipients);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytes_windows() -> Result<()> {
+        let matches = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "-s",
+            "/tmp/a.sock",
+            "-l",
+            "7",
+            "-r",
+            "3600",
+            "-b",
+            "10M",
+            "-l",
+            "100",
+            "-r",
+            "86400",
+            "-b",
+            "100M",
+        ]);
+
+        let m = matches?;
+        let action = handler(&m)?;
+
+        match action {
+            Action::Run { settings, .. } => {
+                assert_eq!(
+                    settings.windows,
+                    vec![
+                        RateLimit {
+                            bytes: Some(10 * 1024 * 1024),
+                            ..RateLimit::new(7, 3600)
+                        },
+                        RateLimit {
+                            bytes: Some(100 * 1024 * 1024),
+                            ..RateLimit::new(100, 86400)
+                        },
+                    ]
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytes_single_value_applies_to_all_windows() -> Result<()> {
+        let matches = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "-s",
+            "/tmp/a.sock",
+            "-l",
+            "7",
+            "-r",
+            "3600",
+            "-l",
+            "100",
+            "-r",
+            "86400",
+            "-b",
+            "1G",
+        ]);
+
+        let m = matches?;
+        let action = handler(&m)?;
+
+        match action {
+            Action::Run { settings, .. } => {
+                assert!(
+                    settings
+                        .windows
+                        .iter()
+                        .all(|window| window.bytes == Some(1024 * 1024 * 1024))
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mismatched_bytes() -> Result<()> {
+        let matches = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "-s",
+            "/tmp/a.sock",
+            "-l",
+            "7",
+            "-r",
+            "3600",
+            "-l",
+            "100",
+            "-r",
+            "86400",
+            "-l",
+            "1000",
+            "-r",
+            "2592000",
+            "-b",
+            "1G",
+            "-b",
+            "2G",
+        ]);
+
+        let m = matches?;
+
+        assert!(handler(&m).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_window_actions() -> Result<()> {
+        let matches = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "-s",
+            "/tmp/a.sock",
+            "-l",
+            "7",
+            "-r",
+            "3600",
+            "--action",
+            "450 4.7.1",
+            "-l",
+            "100",
+            "-r",
+            "86400",
+            "--action",
+            "550 5.7.1",
+            "--message",
+            "hourly limit reached",
+            "--message",
+            "daily limit reached",
+        ]);
+
+        let m = matches?;
+        let action = handler(&m)?;
+
+        match action {
+            Action::Run { settings, .. } => {
+                let actions: Vec<String> = settings
+                    .windows
+                    .iter()
+                    .filter_map(|window| window.action.as_ref().map(ToString::to_string))
+                    .collect();
+                assert_eq!(actions, vec!["450 4.7.1", "550 5.7.1"]);
+
+                let messages: Vec<String> = settings
+                    .windows
+                    .iter()
+                    .filter_map(|window| window.message.as_ref().map(ToString::to_string))
+                    .collect();
+                assert_eq!(
+                    messages,
+                    vec!["hourly limit reached", "daily limit reached"]
+                );
+
+                assert_eq!(settings.action, PolicyAction::Reject);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_action_is_default() -> Result<()> {
+        let matches = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "-s",
+            "/tmp/a.sock",
+            "-l",
+            "7",
+            "-r",
+            "3600",
+            "-l",
+            "100",
+            "-r",
+            "86400",
+            "--action",
+            "DEFER",
+        ]);
+
+        let m = matches?;
+        let action = handler(&m)?;
+
+        match action {
+            Action::Run { settings, .. } => {
+                assert_eq!(settings.action, PolicyAction::Defer);
+                assert!(
+                    settings
+                        .windows
+                        .iter()
+                        .all(|window| window.action.is_none())
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_warn() -> Result<()> {
+        let matches = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "-s",
+            "/tmp/a.sock",
+            "-l",
+            "7",
+            "-r",
+            "3600",
+            "-l",
+            "100",
+            "-r",
+            "86400",
+            "--warn",
+            "80",
+        ]);
+
+        let m = matches?;
+        let action = handler(&m)?;
+
+        match action {
+            Action::Run { settings, .. } => {
+                assert!(
+                    settings
+                        .windows
+                        .iter()
+                        .all(|window| window.warn == Some(80))
+                );
+            }
+        }
+
+        assert!(
+            new()
+                .try_get_matches_from(["bin", "--dsn", "", "--warn", "150"])
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_domain_windows() -> Result<()> {
+        let m =
+            new().try_get_matches_from(["bin", "--dsn", "", "--domain-window", "5000/86400"])?;
+
+        match handler(&m)? {
+            Action::Run { settings, .. } => {
+                assert_eq!(settings.domain_windows, vec![RateLimit::new(5000, 86400)]);
+            }
+        }
+
+        let m = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "--domain-window",
+            "500/86400",
+            "--domain-window",
+            "5000/86400",
+        ])?;
+
+        assert!(handler(&m).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_global_windows() -> Result<()> {
+        let m = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "--global-window",
+            "1000/3600",
+            "--global-action",
+            "DEFER",
+        ])?;
+
+        match handler(&m)? {
+            Action::Run { settings, .. } => {
+                assert_eq!(
+                    settings.global_windows,
+                    vec![RateLimit {
+                        action: Some(PolicyAction::Defer),
+                        ..RateLimit::new(1000, 3600)
+                    }]
+                );
+                assert_eq!(settings.action, PolicyAction::Reject);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recipient_domain_windows() -> Result<()> {
+        let m = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "--recipient-domain-window",
+            "60/60",
+            "--recipient-domain-per-key",
+        ])?;
+
+        match handler(&m)? {
+            Action::Run { settings, .. } => {
+                assert_eq!(
+                    settings.recipient_domain_windows,
+                    vec![RateLimit {
+                        action: Some(PolicyAction::Defer),
+                        ..RateLimit::new(60, 60)
+                    }]
+                );
+                assert!(settings.recipient_domain_per_key);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize() -> Result<()> {
+        let m = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "--normalize",
+            "lowercase,strip-plus",
+            "--default-domain",
+            "example.com",
+        ])?;
+
+        match handler(&m)? {
+            Action::Run { settings, .. } => {
+                assert_eq!(
+                    settings.normalize,
+                    Normalize {
+                        steps: vec![Step::Lowercase, Step::StripPlus],
+                        default_domain: Some("example.com".to_string()),
+                    }
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plans() -> Result<()> {
+        let m = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "--plan",
+            "free=100/3600",
+            "--plan",
+            "bulk=5000/3600,50000/86400",
+            "--plan-action",
+            "free=DEFER",
+            "--assign",
+            "~^news-=bulk",
+            "--assign",
+            "@example.com=free",
+        ])?;
+
+        match handler(&m)? {
+            Action::Run { settings, .. } => {
+                assert_eq!(
+                    settings.plans,
+                    vec![
+                        Plan {
+                            name: "free".to_string(),
+                            windows: vec![RateLimit::new(100, 3600)],
+                            action: Some(PolicyAction::Defer),
+                        },
+                        Plan {
+                            name: "bulk".to_string(),
+                            windows: vec![RateLimit::new(5000, 3600), RateLimit::new(50000, 86400)],
+                            action: None,
+                        },
+                    ]
+                );
+                assert_eq!(
+                    settings
+                        .assignments
+                        .iter()
+                        .map(|assignment| assignment.plan.as_str())
+                        .collect::<Vec<_>>(),
+                    vec!["bulk", "free"]
+                );
+            }
+        }
+
+        for args in [
+            ["--plan", "free=1/60", "--plan", "free=2/60"],
+            ["--plan", "free=1/60", "--plan-action", "paid=DEFER"],
+            ["--plan", "free=1/60", "--assign", "@example.com=paid"],
+        ] {
+            let m = new().try_get_matches_from(["bin", "--dsn", ""].into_iter().chain(args))?;
+            assert!(handler(&m).is_err());
+        }
+
+        // Users sharing a counter key must not be on different plans
+        for (key, valid) in [
+            ("sasl_username+client_address", true),
+            ("sender", true),
+            ("sender_domain", false),
+            ("client_network", false),
+        ] {
+            let m = new().try_get_matches_from([
+                "bin",
+                "--dsn",
+                "",
+                "--key",
+                key,
+                "--plan",
+                "free=1/60",
+                "--plan",
+                "paid=10/60",
+                "--assign",
+                "a@example.com=free",
+                "--assign",
+                "b@example.com=paid",
+            ])?;
+            assert_eq!(handler(&m).is_ok(), valid, "{key}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowlist() -> Result<()> {
+        let m = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "--allow",
+            "monitor@example.com",
+            "--allow",
+            "10.0.0.0/8",
+        ])?;
+
+        match handler(&m)? {
+            Action::Run { settings, .. } => {
+                assert_eq!(settings.allowlist.len(), 2);
+                assert!(settings.blocklist.is_empty());
+            }
+        }
+
+        let m = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "--allowlist",
+            "/nonexistent/allowlist.txt",
+        ])?;
+        assert!(handler(&m).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocklist() -> Result<()> {
+        let m = new().try_get_matches_from([
+            "bin",
+            "--dsn",
+            "",
+            "--block",
+            "compromised@example.com",
+            "--block-action",
+            "554 5.7.1",
+        ])?;
+
+        match handler(&m)? {
+            Action::Run { settings, .. } => {
+                assert_eq!(settings.blocklist.len(), 1);
+                assert_eq!(
+                    settings.block_action,
+                    PolicyAction::Reply {
+                        code: 554,
+                        status: Some("5.7.1".to_string()),
+                    }
+                );
+                assert_eq!(
+                    settings.block_message.to_string(),
+                    "sending blocked by the administrator"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dsn_file() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("policyd-rate-limit-dsn-{}.txt", std::process::id()));
+        fs::write(&path, "postgres://user:secret@db/postfix\n")?;
+
+        let m =
+            new().try_get_matches_from(["bin".as_ref(), "--dsn-file".as_ref(), path.as_os_str()]);
+        let action = m.map_err(anyhow::Error::from).and_then(|m| handler(&m));
+        let _ = fs::remove_file(&path);
+
+        match action? {
+            Action::Run { dsn, .. } => {
+                assert_eq!(dsn.expose_secret(), "postgres://user:secret@db/postfix");
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -5,4 +5,5 @@ mod start;
 pub use self::start::start;
 
 mod commands;
+mod config;
 mod dispatch;
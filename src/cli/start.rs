@@ -1,23 +1,61 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
+use clap::ArgMatches;
+
+use crate::cli::{actions::Action, commands, config, dispatch::handler, telemetry};
+
+/// Options of the config file are parsed as if they were given before the
+/// command line ones
+fn with_config(matches: ArgMatches) -> Result<ArgMatches> {
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Ok(matches);
+    };
+
+    let mut args: Vec<_> = std::env::args_os().collect();
+    args.extend(config::file_args(&commands::new(), path, &matches)?);
+
+    Ok(commands::new().try_get_matches_from(args)?)
+}
+
+fn verbosity_level(matches: &ArgMatches) -> Option<tracing::Level> {
+    match matches.get_count("verbose") {
+        0 => None,
+        1 => Some(tracing::Level::INFO),
+        2 => Some(tracing::Level::DEBUG),
+        _ => Some(tracing::Level::TRACE),
+    }
+}
 
-use crate::cli::{actions::Action, commands, dispatch::handler, telemetry};
 /// Start the CLI.
 ///
 /// # Errors
 /// Returns an error if telemetry initialization or argument dispatch fails.
 pub fn start() -> Result<Action> {
-    let matches = commands::new().get_matches();
+    let matches = with_config(commands::new().get_matches())?;
 
-    let verbosity_level = match matches.get_count("verbose") {
-        0 => None,
-        1 => Some(tracing::Level::INFO),
-        2 => Some(tracing::Level::DEBUG),
-        _ => Some(tracing::Level::TRACE),
-    };
+    telemetry::init(verbosity_level(&matches))?;
+
+    let mut action = handler(&matches)?;
 
-    telemetry::init(verbosity_level)?;
+    let Action::Run { reload: hook, .. } = &mut action;
+    *hook = Some(reload);
+
+    Ok(action)
+}
+
+/// Parse the command line and the config file again, the log level changes
+/// only when the whole configuration is valid.
+///
+/// # Errors
+/// Returns an error if the arguments, the config file or a list file are
+/// invalid.
+pub fn reload() -> Result<Action> {
+    let matches = with_config(commands::new().try_get_matches()?)?;
 
     let action = handler(&matches)?;
 
+    telemetry::set_level(verbosity_level(&matches))?;
+
     Ok(action)
 }
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{sync::OnceLock, time::Duration};
 
 use anyhow::Result;
 use opentelemetry::{KeyValue, global, trace::TracerProvider as _};
@@ -8,7 +8,10 @@ use opentelemetry_sdk::{
     trace::{SdkTracerProvider, Tracer},
 };
 use tracing::Level;
-use tracing_subscriber::{EnvFilter, Registry, fmt, layer::SubscriberExt};
+use tracing_subscriber::{EnvFilter, Registry, fmt, layer::SubscriberExt, reload};
+
+/// Handle to replace the filter of the running subscriber, see [`set_level`].
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
 
 fn init_tracer() -> Result<Tracer> {
     let tracer_provider = SdkTracerProvider::builder()
@@ -33,12 +36,23 @@ fn init_tracer() -> Result<Tracer> {
     Ok(tracer_provider.tracer(env!("CARGO_PKG_NAME")))
 }
 
+// RUST_LOG=
+fn filter(verbosity_level: Option<Level>) -> Result<EnvFilter> {
+    let verbosity_level = verbosity_level.unwrap_or(Level::ERROR);
+
+    Ok(EnvFilter::builder()
+        .with_default_directive(verbosity_level.into())
+        .from_env_lossy()
+        .add_directive("hyper=error".parse()?)
+        .add_directive("tokio=error".parse()?)
+        .add_directive("reqwest=error".parse()?)
+        .add_directive("opentelemetry_sdk=warn".parse()?))
+}
+
 /// Start the telemetry layer
 /// # Errors
 /// Will return an error if the telemetry layer fails to start
 pub fn init(verbosity_level: Option<Level>) -> Result<()> {
-    let verbosity_level = verbosity_level.unwrap_or(Level::ERROR);
-
     let tracer = init_tracer()?;
 
     let otel_tracer_layer = tracing_opentelemetry::layer().with_tracer(tracer);
@@ -51,19 +65,25 @@ pub fn init(verbosity_level: Option<Level>) -> Result<()> {
         .with_target(false)
         .pretty();
 
-    // RUST_LOG=
-    let filter = EnvFilter::builder()
-        .with_default_directive(verbosity_level.into())
-        .from_env_lossy()
-        .add_directive("hyper=error".parse()?)
-        .add_directive("tokio=error".parse()?)
-        .add_directive("reqwest=error".parse()?)
-        .add_directive("opentelemetry_sdk=warn".parse()?);
+    let (filter, handle) = reload::Layer::new(filter(verbosity_level)?);
+    let _ = FILTER.set(handle);
 
     let subscriber = Registry::default()
+        .with(filter)
         .with(fmt_layer)
-        .with(otel_tracer_layer)
-        .with(filter);
+        .with(otel_tracer_layer);
 
     Ok(tracing::subscriber::set_global_default(subscriber)?)
 }
+
+/// Change the log level of the running telemetry layer, nothing happens
+/// when it was not started.
+/// # Errors
+/// Will return an error if the filter can not be replaced
+pub fn set_level(verbosity_level: Option<Level>) -> Result<()> {
+    if let Some(handle) = FILTER.get() {
+        handle.reload(filter(verbosity_level)?)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+/// Units of a window rate with their length in seconds, longest first.
+/// Only lower case is accepted so `M` is never mistaken for months.
+const UNITS: [(char, i64); 5] = [
+    ('w', 604_800),
+    ('d', 86_400),
+    ('h', 3_600),
+    ('m', 60),
+    ('s', 1),
+];
+
+/// Longest window rate accepted, 365 days.
+pub const MAX_RATE: i32 = 31_536_000;
+
+fn plural(value: i64, unit: &str) -> String {
+    if value == 1 {
+        format!("{value} {unit}")
+    } else {
+        format!("{value} {unit}s")
+    }
+}
+
+/// Describe a number of seconds for humans, e.g. `37 minutes` or
+/// `1 hour 5 minutes`. Partial minutes are rounded up so the hint never
+/// suggests retrying too early.
+#[must_use]
+pub fn humanize(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    if seconds < 60 {
+        return plural(seconds, "second");
+    }
+
+    let minutes = (seconds + 59) / 60;
+    if minutes < 60 {
+        return plural(minutes, "minute");
+    }
+
+    let (hours, minutes) = (minutes / 60, minutes % 60);
+    if hours < 24 {
+        return if minutes == 0 {
+            plural(hours, "hour")
+        } else {
+            format!("{} {}", plural(hours, "hour"), plural(minutes, "minute"))
+        };
+    }
+
+    let (days, hours) = (hours / 24, hours % 24);
+    if hours == 0 {
+        plural(days, "day")
+    } else {
+        format!("{} {}", plural(days, "day"), plural(hours, "hour"))
+    }
+}
+
+/// Parse a window rate given in seconds or as a duration like `30m`, `1h`,
+/// `1d`, `1w` or `1h30m`, from one second up to [`MAX_RATE`].
+///
+/// # Errors
+/// Returns an error if the rate is malformed or out of bounds.
+pub fn parse_rate(rate: &str) -> Result<i32, String> {
+    let rate = rate.trim();
+    let invalid =
+        || format!("Invalid rate: {rate}, expected seconds or a duration like 30m, 1h, 1d or 1w");
+
+    let seconds = if let Ok(seconds) = rate.parse::<i64>() {
+        seconds
+    } else {
+        let mut seconds: i64 = 0;
+        let mut rest = rate;
+
+        while !rest.is_empty() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(invalid)?;
+            let (value, unit) = rest.split_at(end);
+            let value = value.parse::<i64>().map_err(|_| invalid())?;
+
+            let mut chars = unit.chars();
+            let unit = chars
+                .next()
+                .and_then(|unit| UNITS.iter().find(|(name, _)| *name == unit))
+                .map(|(_, length)| *length)
+                .ok_or_else(invalid)?;
+
+            seconds = value
+                .checked_mul(unit)
+                .and_then(|value| seconds.checked_add(value))
+                .ok_or_else(invalid)?;
+            rest = chars.as_str();
+        }
+
+        seconds
+    };
+
+    i32::try_from(seconds)
+        .ok()
+        .filter(|seconds| (1..=MAX_RATE).contains(seconds))
+        .ok_or_else(|| format!("Invalid rate: {rate}, must be between 1s and 365d"))
+}
+
+/// Write a window rate in the form [`parse_rate`] accepts, e.g. `1d` or
+/// `1h30m`.
+#[must_use]
+pub fn format_rate(seconds: i64) -> String {
+    if seconds <= 0 {
+        return "0s".to_string();
+    }
+
+    let mut rest = seconds;
+    UNITS
+        .iter()
+        .filter_map(|(name, length)| {
+            let value = rest / length;
+            rest %= length;
+
+            (value > 0).then(|| format!("{value}{name}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_humanize() {
+        assert_eq!(humanize(-5), "0 seconds");
+        assert_eq!(humanize(1), "1 second");
+        assert_eq!(humanize(59), "59 seconds");
+        assert_eq!(humanize(60), "1 minute");
+        assert_eq!(humanize(2201), "37 minutes");
+        assert_eq!(humanize(3600), "1 hour");
+        assert_eq!(humanize(3900), "1 hour 5 minutes");
+        assert_eq!(humanize(86400), "1 day");
+        assert_eq!(humanize(2 * 86400 + 3 * 3600), "2 days 3 hours");
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("86400"), Ok(86_400));
+        assert_eq!(parse_rate("30m"), Ok(1_800));
+        assert_eq!(parse_rate(" 1h "), Ok(3_600));
+        assert_eq!(parse_rate("1d"), Ok(86_400));
+        assert_eq!(parse_rate("1w"), Ok(604_800));
+        assert_eq!(parse_rate("1h30m"), Ok(5_400));
+        assert_eq!(parse_rate("365d"), Ok(MAX_RATE));
+
+        for rate in ["", "0", "0s", "-1", "h", "1x", "1H", "1h30", "366d", "1.5h"] {
+            assert!(parse_rate(rate).is_err(), "{rate}");
+        }
+    }
+
+    #[test]
+    fn test_format_rate() {
+        assert_eq!(format_rate(0), "0s");
+        assert_eq!(format_rate(45), "45s");
+        assert_eq!(format_rate(3_600), "1h");
+        assert_eq!(format_rate(5_400), "1h30m");
+        assert_eq!(format_rate(8_640), "2h24m");
+        assert_eq!(format_rate(86_400), "1d");
+        assert_eq!(format_rate(2_592_000), "4w2d");
+
+        for rate in ["90s", "1h30m", "1w1d", "365d"] {
+            assert_eq!(
+                parse_rate(rate).map(|seconds| parse_rate(&format_rate(i64::from(seconds)))),
+                Ok(parse_rate(rate)),
+                "{rate}"
+            );
+        }
+    }
+}
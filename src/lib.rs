@@ -1,8 +1,48 @@
+use std::fmt;
+
+use crate::{
+    duration::format_rate,
+    policy::{PolicyAction, Template},
+};
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct RateLimit {
     pub limit: i32,
     pub rate: i32,
+    /// Maximum bytes per rate window, `None` for no byte limit.
+    pub bytes: Option<i64>,
+    /// Action when this window is exceeded, `None` for the default action.
+    pub action: Option<PolicyAction>,
+    /// Reply text when this window is exceeded, `None` for the default message.
+    pub message: Option<Template>,
+    /// Usage in percent of the quota from which accepted requests get a
+    /// warning header, `None` for no warning.
+    pub warn: Option<u8>,
+}
+
+impl RateLimit {
+    /// Create a message count window of `limit` messages per `rate` seconds.
+    #[must_use]
+    pub const fn new(limit: i32, rate: i32) -> Self {
+        Self {
+            limit,
+            rate,
+            bytes: None,
+            action: None,
+            message: None,
+            warn: None,
+        }
+    }
+}
+
+/// Write the window as `LIMIT/RATE`, e.g. `100/1h`.
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.limit, format_rate(i64::from(self.rate)))
+    }
 }
 
 pub mod cli;
+pub mod duration;
+pub mod policy;
 pub mod queries;
@@ -0,0 +1,275 @@
+use std::{fmt, str::FromStr};
+
+use crate::duration::humanize;
+
+/// Action returned to Postfix when a request is over quota.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum PolicyAction {
+    #[default]
+    Reject,
+    Defer,
+    DeferIfPermit,
+    Hold,
+    Discard,
+    /// Explicit SMTP reply code, optionally with an enhanced status code,
+    /// e.g. `450 4.7.1`.
+    Reply {
+        code: u16,
+        status: Option<String>,
+    },
+}
+
+impl PolicyAction {
+    /// Build the `action=...` line sent to Postfix with the given reply text.
+    #[must_use]
+    pub fn response(&self, text: &str) -> String {
+        let text = text.trim();
+        if text.is_empty() {
+            format!("action={self}")
+        } else {
+            format!("action={self} {text}")
+        }
+    }
+}
+
+fn parse_status(code: u16, status: &str) -> Result<String, String> {
+    let parts: Vec<&str> = status.split('.').collect();
+
+    let valid = match parts.as_slice() {
+        [class, subject, detail] => {
+            class.parse::<u16>().ok() == Some(code / 100)
+                && [subject, detail].iter().all(|part| {
+                    !part.is_empty() && part.len() <= 3 && part.bytes().all(|b| b.is_ascii_digit())
+                })
+        }
+        _ => false,
+    };
+
+    if valid {
+        Ok(status.to_string())
+    } else {
+        Err(format!(
+            "invalid enhanced status code {status} for {code}, expected {}.X.Y",
+            code / 100
+        ))
+    }
+}
+
+impl FromStr for PolicyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let action = parts.next().unwrap_or_default();
+
+        let parsed = match action.to_ascii_uppercase().as_str() {
+            "REJECT" => Self::Reject,
+            "DEFER" => Self::Defer,
+            "DEFER_IF_PERMIT" => Self::DeferIfPermit,
+            "HOLD" => Self::Hold,
+            "DISCARD" => Self::Discard,
+            code => {
+                let code = code
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|code| (400..600).contains(code))
+                    .ok_or_else(|| {
+                        format!(
+                            "invalid action: {s}, expected REJECT, DEFER, DEFER_IF_PERMIT, HOLD, \
+                             DISCARD or a 4xx/5xx reply code"
+                        )
+                    })?;
+                let status = parts
+                    .next()
+                    .map(|status| parse_status(code, status))
+                    .transpose()?;
+
+                Self::Reply { code, status }
+            }
+        };
+
+        if parts.next().is_some() {
+            return Err(format!(
+                "invalid action: {s}, use the message for the reply text"
+            ));
+        }
+
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reject => f.write_str("REJECT"),
+            Self::Defer => f.write_str("DEFER"),
+            Self::DeferIfPermit => f.write_str("DEFER_IF_PERMIT"),
+            Self::Hold => f.write_str("HOLD"),
+            Self::Discard => f.write_str("DISCARD"),
+            Self::Reply {
+                code,
+                status: Some(status),
+            } => write!(f, "{code} {status}"),
+            Self::Reply { code, status: None } => write!(f, "{code}"),
+        }
+    }
+}
+
+/// Values available to a reply template.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TemplateVars<'a> {
+    pub user: &'a str,
+    pub window: i32,
+    pub quota: i32,
+    pub retry_after: i64,
+}
+
+const PLACEHOLDERS: [&str; 5] = ["user", "window", "quota", "retry_after", "retry_in"];
+
+/// Reply text with `{user}`, `{window}`, `{quota}`, `{retry_after}` (seconds)
+/// and `{retry_in}` (e.g. `37 minutes`) placeholders.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template(String);
+
+impl Default for Template {
+    fn default() -> Self {
+        Self("sending limit exceeded, try again in {retry_in}".to_string())
+    }
+}
+
+impl Template {
+    /// Replace the placeholders with `vars`.
+    #[must_use]
+    pub fn render(&self, vars: &TemplateVars<'_>) -> String {
+        let rendered = self
+            .0
+            .replace("{user}", vars.user)
+            .replace("{window}", &vars.window.to_string())
+            .replace("{quota}", &vars.quota.to_string())
+            .replace("{retry_after}", &vars.retry_after.to_string())
+            .replace("{retry_in}", &humanize(vars.retry_after));
+
+        // The reply is a single protocol line, never let a value break it
+        rendered
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect()
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.chars().any(char::is_control) {
+            return Err("message must be a single line".to_string());
+        }
+
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let after = rest.get(start + 1..).unwrap_or_default();
+            let Some(end) = after.find('}') else {
+                return Err(format!("unterminated placeholder in message: {s}"));
+            };
+            let name = after.get(..end).unwrap_or_default();
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(format!(
+                    "unknown placeholder {{{name}}} in message, expected one of {}",
+                    PLACEHOLDERS.map(|p| format!("{{{p}}}")).join(", ")
+                ));
+            }
+            rest = after.get(end + 1..).unwrap_or_default();
+        }
+
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!("REJECT".parse(), Ok(PolicyAction::Reject));
+        assert_eq!("defer".parse(), Ok(PolicyAction::Defer));
+        assert_eq!("DEFER_IF_PERMIT".parse(), Ok(PolicyAction::DeferIfPermit));
+        assert_eq!("HOLD".parse(), Ok(PolicyAction::Hold));
+        assert_eq!("DISCARD".parse(), Ok(PolicyAction::Discard));
+        assert_eq!(
+            "450".parse(),
+            Ok(PolicyAction::Reply {
+                code: 450,
+                status: None
+            })
+        );
+        assert_eq!(
+            "550 5.7.1".parse(),
+            Ok(PolicyAction::Reply {
+                code: 550,
+                status: Some("5.7.1".to_string())
+            })
+        );
+
+        assert!("OK".parse::<PolicyAction>().is_err());
+        assert!("250".parse::<PolicyAction>().is_err());
+        assert!("450 5.7.1".parse::<PolicyAction>().is_err());
+        assert!("450 4.7".parse::<PolicyAction>().is_err());
+        assert!("450 4.7.1 slow down".parse::<PolicyAction>().is_err());
+    }
+
+    #[test]
+    fn test_action_response() {
+        assert_eq!(
+            PolicyAction::Reject.response("sending limit exceeded"),
+            "action=REJECT sending limit exceeded"
+        );
+        assert_eq!(PolicyAction::Discard.response(""), "action=DISCARD");
+        assert_eq!(
+            PolicyAction::Reply {
+                code: 450,
+                status: Some("4.7.1".to_string())
+            }
+            .response("try later"),
+            "action=450 4.7.1 try later"
+        );
+    }
+
+    #[test]
+    fn test_template() -> Result<(), String> {
+        let template: Template =
+            "{user} sent {quota} messages in {window}s, retry in {retry_after}s".parse()?;
+
+        assert_eq!(
+            template.render(&TemplateVars {
+                user: "user@example.com",
+                window: 3600,
+                quota: 100,
+                retry_after: 120,
+            }),
+            "user@example.com sent 100 messages in 3600s, retry in 120s"
+        );
+
+        assert_eq!(
+            Template::default().render(&TemplateVars {
+                user: "user@example.com",
+                window: 3600,
+                quota: 100,
+                retry_after: 2201,
+            }),
+            "sending limit exceeded, try again in 37 minutes"
+        );
+
+        assert!("{nope}".parse::<Template>().is_err());
+        assert!("{user".parse::<Template>().is_err());
+        assert!("two\nlines".parse::<Template>().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use crate::policy::{PolicyRequest, ProtocolState, Settings};
+
+/// Prefix length used to aggregate IPv4 client addresses.
+pub const IPV4_PREFIX: u8 = 24;
+/// Prefix length used to aggregate IPv6 client addresses.
+pub const IPV6_PREFIX: u8 = 64;
+
+/// Network of `ip` in CIDR notation, `/24` for IPv4 and `/64` for IPv6.
+#[must_use]
+pub fn client_network(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX << (32 - u32::from(IPV4_PREFIX));
+            let network = std::net::Ipv4Addr::from(u32::from(ip) & mask);
+            format!("{network}/{IPV4_PREFIX}")
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX << (128 - u32::from(IPV6_PREFIX));
+            let network = std::net::Ipv6Addr::from(u128::from(ip) & mask);
+            format!("{network}/{IPV6_PREFIX}")
+        }
+    }
+}
+
+/// Longest key stored, the size of the `username` columns.
+pub const MAX_KEY_LEN: usize = 128;
+
+/// `key` cut to [`MAX_KEY_LEN`] characters. Longer keys keep their start
+/// followed by a hash of the whole key, so different keys with the same
+/// prefix still get their own counters.
+#[must_use]
+pub fn bound_key(key: String) -> String {
+    if key.chars().count() <= MAX_KEY_LEN {
+        return key;
+    }
+
+    // FNV-1a, stable across builds unlike the std hasher, as the keys are
+    // stored in the database
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+
+    let prefix: String = key.chars().take(MAX_KEY_LEN - 17).collect();
+    format!("{prefix}#{hash:016x}")
+}
+
+/// Domain part of an email address, lower-cased.
+#[must_use]
+pub fn domain_of(address: &str) -> Option<String> {
+    address
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim_end_matches('.').to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+}
+
+/// A request attribute that can be part of the rate limit key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyPart {
+    SaslUsername,
+    Sender,
+    SenderDomain,
+    ClientAddress,
+    /// The client address aggregated to its /24 (IPv4) or /64 (IPv6) network.
+    ClientNetwork,
+}
+
+impl KeyPart {
+    const ALL: [Self; 5] = [
+        Self::SaslUsername,
+        Self::Sender,
+        Self::SenderDomain,
+        Self::ClientAddress,
+        Self::ClientNetwork,
+    ];
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::SaslUsername => "sasl_username",
+            Self::Sender => "sender",
+            Self::SenderDomain => "sender_domain",
+            Self::ClientAddress => "client_address",
+            Self::ClientNetwork => "client_network",
+        }
+    }
+
+    fn value(self, request: &PolicyRequest) -> Option<String> {
+        match self {
+            Self::SaslUsername => request.sasl_username.clone(),
+            Self::Sender => request.sender.clone(),
+            Self::SenderDomain => request.sender.as_deref().and_then(domain_of),
+            Self::ClientAddress => request.client_address.map(|ip| ip.to_string()),
+            Self::ClientNetwork => request.client_address.map(client_network),
+        }
+    }
+}
+
+/// Which request attributes identify the sender being rate limited, e.g.
+/// `sasl_username` or `sasl_username+client_address`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Key(Vec<KeyPart>);
+
+impl Default for Key {
+    fn default() -> Self {
+        Self(vec![KeyPart::SaslUsername])
+    }
+}
+
+impl Key {
+    /// Build the key for `request`, `None` when one of the attributes is
+    /// missing or empty. The values of combined keys are joined with `+`,
+    /// see [`bound_key`] for long ones.
+    #[must_use]
+    pub fn render(&self, request: &PolicyRequest) -> Option<String> {
+        let values = self
+            .0
+            .iter()
+            .map(|part| part.value(request))
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(bound_key(values.join("+")))
+    }
+
+    /// Whether the key identifies a single user, i.e. has the SASL username
+    /// or the sender. Keys like `sender_domain` share one counter between
+    /// several users.
+    #[must_use]
+    pub fn is_per_user(&self) -> bool {
+        self.user_part().is_some()
+    }
+
+    /// The SASL username, or the sender when the key has no SASL username,
+    /// of `request`. `None` when the key is not per user.
+    #[must_use]
+    pub fn user(&self, request: &PolicyRequest) -> Option<String> {
+        self.user_part().and_then(|part| part.value(request))
+    }
+
+    fn user_part(&self) -> Option<KeyPart> {
+        [KeyPart::SaslUsername, KeyPart::Sender]
+            .into_iter()
+            .find(|part| self.0.contains(part))
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+
+        for name in s.split('+').map(str::trim) {
+            let part = KeyPart::ALL
+                .into_iter()
+                .find(|part| part.name() == name)
+                .ok_or_else(|| {
+                    format!(
+                        "invalid key attribute: {name:?}, expected {}",
+                        KeyPart::ALL.map(KeyPart::name).join(", ")
+                    )
+                })?;
+
+            if parts.contains(&part) {
+                return Err(format!("duplicate key attribute: {name}"));
+            }
+            parts.push(part);
+        }
+
+        Ok(Self(parts))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.0.iter().map(|part| part.name()).collect();
+        f.write_str(&names.join("+"))
+    }
+}
+
+/// Counters a request is checked against, the key's own windows and the
+/// ones shared with other keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// The request's [`Key`].
+    Key,
+    /// Every mailbox of the SASL username's domain, or the sender's domain
+    /// for requests without SASL username. Bare logins only have one with
+    /// `--default-domain`.
+    Domain,
+    /// Every request, a single server-wide counter.
+    Global,
+    /// Every recipient in the same domain, or per key and recipient domain,
+    /// only checked in the RCPT state.
+    RecipientDomain,
+    /// Every unauthenticated client of the same /24 (IPv4) or /64 (IPv6)
+    /// network.
+    Client,
+}
+
+impl Scope {
+    pub const ALL: [Self; 5] = [
+        Self::Key,
+        Self::Domain,
+        Self::Global,
+        Self::RecipientDomain,
+        Self::Client,
+    ];
+
+    /// Name used in the logs.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Key => "key",
+            Self::Domain => "domain",
+            Self::Global => "global",
+            Self::RecipientDomain => "recipient_domain",
+            Self::Client => "client",
+        }
+    }
+
+    /// Counter key of the scope for `request`, `None` when the request lacks
+    /// the attributes it needs. Shared scopes are prefixed with their name so
+    /// they never collide with a [`Key`].
+    #[must_use]
+    pub fn render(self, settings: &Settings, request: &PolicyRequest) -> Option<String> {
+        self.render_unbounded(settings, request).map(bound_key)
+    }
+
+    fn render_unbounded(self, settings: &Settings, request: &PolicyRequest) -> Option<String> {
+        match self {
+            Self::Key => settings.key.render(request),
+            // The sender is chosen by the client, authenticated users are
+            // only counted by the domain of their SASL username
+            Self::Domain => match request.sasl_username.as_deref() {
+                Some(username) => domain_of(username),
+                None => request.sender.as_deref().and_then(domain_of),
+            }
+            .map(|domain| format!("{}:{domain}", self.name())),
+            Self::Global => Some(format!("{}:*", self.name())),
+            Self::RecipientDomain => {
+                if request.protocol_state != Some(ProtocolState::Rcpt) {
+                    return None;
+                }
+
+                let domain = request.recipient.as_deref().and_then(domain_of)?;
+                if settings.recipient_domain_per_key {
+                    let key = settings.key.render(request)?;
+                    Some(format!("{}:{key}+{domain}", self.name()))
+                } else {
+                    Some(format!("{}:{domain}", self.name()))
+                }
+            }
+            Self::Client => {
+                if request.sasl_username.is_some() {
+                    return None;
+                }
+
+                request
+                    .client_address
+                    .map(|ip| format!("{}:{}", self.name(), client_network(ip)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> PolicyRequest {
+        PolicyRequest {
+            sasl_username: Some("user@example.com".to_string()),
+            sender: Some("Sender@Example.COM".to_string()),
+            client_address: "192.0.2.77".parse().ok(),
+            ..PolicyRequest::default()
+        }
+    }
+
+    #[test]
+    fn test_client_network() -> Result<(), std::net::AddrParseError> {
+        assert_eq!(client_network("192.0.2.77".parse()?), "192.0.2.0/24");
+        assert_eq!(
+            client_network("2001:db8:1:2:3:4:5:6".parse()?),
+            "2001:db8:1:2::/64"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bound_key() {
+        assert_eq!(
+            bound_key("user@example.com".to_string()),
+            "user@example.com"
+        );
+
+        let local = "a".repeat(120);
+        let first = bound_key(format!("{local}@one.example"));
+        let second = bound_key(format!("{local}@two.example"));
+        assert_eq!(first.chars().count(), MAX_KEY_LEN);
+        assert_eq!(second.chars().count(), MAX_KEY_LEN);
+        assert!(first.starts_with(&local[..MAX_KEY_LEN - 17]));
+        assert_ne!(first, second);
+        assert_eq!(first, bound_key(format!("{local}@one.example")));
+
+        // Multi-byte characters are counted once, as by the database
+        let key = bound_key("ü".repeat(200));
+        assert_eq!(key.chars().count(), MAX_KEY_LEN);
+    }
+
+    #[test]
+    fn test_render() -> Result<(), String> {
+        let request = request();
+
+        assert_eq!(
+            Key::default().render(&request).as_deref(),
+            Some("user@example.com")
+        );
+        assert_eq!(
+            "sender_domain".parse::<Key>()?.render(&request).as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            "client_network".parse::<Key>()?.render(&request).as_deref(),
+            Some("192.0.2.0/24")
+        );
+        assert_eq!(
+            "sasl_username+client_address"
+                .parse::<Key>()?
+                .render(&request)
+                .as_deref(),
+            Some("user@example.com+192.0.2.77")
+        );
+
+        // Relays in mynetworks never authenticate
+        let relay = PolicyRequest {
+            sasl_username: None,
+            ..request
+        };
+        assert_eq!(Key::default().render(&relay), None);
+        assert_eq!(
+            "client_address".parse::<Key>()?.render(&relay).as_deref(),
+            Some("192.0.2.77")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_render() {
+        let request = request();
+        let settings = Settings::default();
+
+        assert_eq!(
+            Scope::Key.render(&settings, &request).as_deref(),
+            Some("user@example.com")
+        );
+        assert_eq!(
+            Scope::Domain.render(&settings, &request).as_deref(),
+            Some("domain:example.com")
+        );
+
+        // Bare logins can not charge the domain of a sender they choose
+        let local = PolicyRequest {
+            sasl_username: Some("user".to_string()),
+            sender: Some("user@Tenant.example".to_string()),
+            ..request
+        };
+        assert_eq!(Scope::Domain.render(&settings, &local), None);
+        assert_eq!(
+            Scope::Global.render(&settings, &local).as_deref(),
+            Some("global:*")
+        );
+
+        // Unauthenticated requests are counted by the sender's domain
+        let unauthenticated = PolicyRequest {
+            sasl_username: None,
+            ..local
+        };
+        assert_eq!(
+            Scope::Domain.render(&settings, &unauthenticated).as_deref(),
+            Some("domain:tenant.example")
+        );
+    }
+
+    #[test]
+    fn test_recipient_domain_scope() {
+        let mut request = PolicyRequest {
+            recipient: Some("someone@Gmail.com".to_string()),
+            ..request()
+        };
+        let mut settings = Settings::default();
+
+        // Recipients are only known for sure in the RCPT state
+        assert_eq!(Scope::RecipientDomain.render(&settings, &request), None);
+
+        request.protocol_state = Some(ProtocolState::Rcpt);
+        assert_eq!(
+            Scope::RecipientDomain
+                .render(&settings, &request)
+                .as_deref(),
+            Some("recipient_domain:gmail.com")
+        );
+
+        settings.recipient_domain_per_key = true;
+        assert_eq!(
+            Scope::RecipientDomain
+                .render(&settings, &request)
+                .as_deref(),
+            Some("recipient_domain:user@example.com+gmail.com")
+        );
+    }
+
+    #[test]
+    fn test_client_scope() {
+        let settings = Settings::default();
+
+        // Authenticated clients are limited by their key
+        assert_eq!(Scope::Client.render(&settings, &request()), None);
+
+        let incoming = PolicyRequest {
+            sasl_username: None,
+            client_address: "2001:db8:1:2:3:4:5:6".parse().ok(),
+            ..request()
+        };
+        assert_eq!(
+            Scope::Client.render(&settings, &incoming).as_deref(),
+            Some("client:2001:db8:1:2::/64")
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "sasl_username+client_address"
+                .parse::<Key>()
+                .map(|key| key.to_string()),
+            Ok("sasl_username+client_address".to_string())
+        );
+        assert!("recipient".parse::<Key>().is_err());
+        assert!("sender+sender".parse::<Key>().is_err());
+        assert!("".parse::<Key>().is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::{fmt, fs, net::IpAddr, path::PathBuf, str::FromStr};
+
+use anyhow::{Context, Result, anyhow};
+use ipnet::IpNet;
+
+use crate::policy::{Matcher, PolicyRequest};
+
+/// An entry of an [`AddressList`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Entry {
+    /// SASL usernames and senders, see [`Matcher`].
+    Address(Matcher),
+    /// Client addresses within a network, e.g. `192.0.2.0/24`.
+    Network(IpNet),
+}
+
+impl Entry {
+    /// Whether the entry matches `request`. Addresses are compared with the
+    /// SASL username and the sender, or only the SASL username when
+    /// `authenticated_only` is set, as the sender is chosen by the client.
+    fn matches(&self, request: &PolicyRequest, authenticated_only: bool) -> bool {
+        match self {
+            Self::Address(matcher) => {
+                let sender = if authenticated_only {
+                    None
+                } else {
+                    request.sender.as_ref()
+                };
+
+                request
+                    .sasl_username
+                    .iter()
+                    .chain(sender)
+                    .any(|address| matcher.matches(address))
+            }
+            Self::Network(network) => request
+                .client_address
+                .is_some_and(|ip| network.contains(&ip)),
+        }
+    }
+}
+
+/// Parse a network or single IP address, otherwise a user, `@domain` or
+/// `~REGEX` like [`Matcher`].
+impl FromStr for Entry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Ok(network) = s.parse::<IpNet>() {
+            return Ok(Self::Network(network.trunc()));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Self::Network(IpNet::from(ip)));
+        }
+        if s.contains('/') && !s.starts_with('~') {
+            return Err(format!("invalid network: {s}"));
+        }
+
+        s.parse().map(Self::Address)
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address(matcher) => matcher.fmt(f),
+            Self::Network(network) => network.fmt(f),
+        }
+    }
+}
+
+/// Users, domains and client networks given on the command line or loaded
+/// from files, one entry per line.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AddressList {
+    /// Entries given on the command line.
+    pub entries: Vec<Entry>,
+    /// Files the remaining entries are loaded from, see [`AddressList::reload`].
+    pub files: Vec<PathBuf>,
+    loaded: Vec<Entry>,
+}
+
+impl AddressList {
+    /// Create a list of `entries` and the entries of `files`.
+    ///
+    /// # Errors
+    /// Returns an error if a file can not be read or has an invalid entry.
+    pub fn new(entries: Vec<Entry>, files: Vec<PathBuf>) -> Result<Self> {
+        Self {
+            entries,
+            files,
+            loaded: Vec::new(),
+        }
+        .reload()
+    }
+
+    /// The same list with the entries of its files read again.
+    ///
+    /// # Errors
+    /// Returns an error if a file can not be read or has an invalid entry.
+    pub fn reload(&self) -> Result<Self> {
+        let mut loaded = Vec::new();
+
+        for file in &self.files {
+            let content = fs::read_to_string(file)
+                .with_context(|| format!("failed to read {}", file.display()))?;
+
+            for (number, line) in content.lines().enumerate() {
+                // Comments start with #, regular expressions can not contain it
+                let line = line.split('#').next().unwrap_or_default().trim();
+                if line.is_empty() {
+                    continue;
+                }
+
+                loaded.push(line.parse().map_err(|e| {
+                    anyhow!("{}:{}: {e}", file.display(), number.saturating_add(1))
+                })?);
+            }
+        }
+
+        Ok(Self {
+            loaded,
+            ..self.clone()
+        })
+    }
+
+    /// Number of entries, including the ones loaded from files.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len().saturating_add(self.loaded.len())
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// First entry matching the SASL username, the sender or the client
+    /// address of `request`.
+    #[must_use]
+    pub fn find(&self, request: &PolicyRequest) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .chain(&self.loaded)
+            .find(|entry| entry.matches(request, false))
+    }
+
+    /// Like [`AddressList::find`], but without the sender: addresses only
+    /// match the SASL username, unauthenticated requests only client
+    /// networks. Used for exemptions, so no client can claim a listed
+    /// sender.
+    #[must_use]
+    pub fn find_authenticated(&self, request: &PolicyRequest) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .chain(&self.loaded)
+            .find(|entry| entry.matches(request, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(user: &str, client: &str) -> PolicyRequest {
+        PolicyRequest {
+            sasl_username: Some(user.to_string()),
+            client_address: client.parse().ok(),
+            ..PolicyRequest::default()
+        }
+    }
+
+    #[test]
+    fn test_entry() -> Result<(), String> {
+        assert_eq!(
+            "192.0.2.77/24".parse::<Entry>()?.to_string(),
+            "192.0.2.0/24"
+        );
+        assert_eq!(
+            "2001:db8::1".parse::<Entry>()?.to_string(),
+            "2001:db8::1/128"
+        );
+        assert_eq!(
+            "*@Example.com".parse::<Entry>()?,
+            Entry::Address(Matcher::Domain("example.com".to_string()))
+        );
+        assert!("192.0.2.0/33".parse::<Entry>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find() -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "policyd-rate-limit-list-{}.txt",
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            "# internal relays\n10.0.0.0/8\n\nmonitor@example.com # probes\n",
+        )?;
+
+        let list = AddressList::new(
+            vec!["@tickets.example".parse().map_err(anyhow::Error::msg)?],
+            vec![path.clone()],
+        );
+        let _ = fs::remove_file(&path);
+        let list = list?;
+
+        assert_eq!(list.len(), 3);
+        assert!(
+            list.find(&request("user@tickets.example", "192.0.2.1"))
+                .is_some()
+        );
+        assert!(
+            list.find(&request("Monitor@example.com", "192.0.2.1"))
+                .is_some()
+        );
+        assert!(
+            list.find(&request("user@example.com", "10.1.2.3"))
+                .is_some()
+        );
+        assert!(
+            list.find(&request("user@example.com", "192.0.2.1"))
+                .is_none()
+        );
+
+        // Authenticated requests can not claim a listed sender
+        let spoofed = PolicyRequest {
+            sender: Some("monitor@example.com".to_string()),
+            ..request("attacker@example.net", "192.0.2.1")
+        };
+        assert!(list.find(&spoofed).is_some());
+        assert!(list.find_authenticated(&spoofed).is_none());
+
+        // Neither can unauthenticated ones, only their network is exempt
+        let unauthenticated = PolicyRequest {
+            sasl_username: None,
+            ..spoofed
+        };
+        assert!(list.find(&unauthenticated).is_some());
+        assert!(list.find_authenticated(&unauthenticated).is_none());
+        let relay = PolicyRequest {
+            client_address: "10.1.2.3".parse().ok(),
+            ..unauthenticated
+        };
+        assert!(list.find_authenticated(&relay).is_some());
+
+        // Unreadable files fail the reload, callers keep the previous list
+        assert!(list.reload().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,15 @@
+pub mod action;
+pub mod key;
+pub mod list;
+pub mod normalize;
+pub mod plan;
+pub mod request;
+pub mod settings;
+
+pub use self::action::{PolicyAction, Template, TemplateVars};
+pub use self::key::{Key, Scope};
+pub use self::list::{AddressList, Entry};
+pub use self::normalize::Normalize;
+pub use self::plan::{Assignment, Matcher, Plan};
+pub use self::request::{ParseError, PolicyRequest, ProtocolState};
+pub use self::settings::{Charge, Settings};
@@ -0,0 +1,186 @@
+use std::str::FromStr;
+
+use crate::policy::PolicyRequest;
+
+/// A normalization step, see [`Normalize`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Remove leading and trailing whitespace.
+    Trim,
+    /// Lower-case the whole address.
+    Lowercase,
+    /// Remove the `+tag` sub-address from the local part.
+    StripPlus,
+    /// Convert internationalized domains to punycode.
+    Idn,
+}
+
+impl FromStr for Step {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trim" => Ok(Self::Trim),
+            "lowercase" => Ok(Self::Lowercase),
+            "strip-plus" => Ok(Self::StripPlus),
+            "idn" => Ok(Self::Idn),
+            _ => Err(format!(
+                "invalid normalization: {s}, expected trim, lowercase, strip-plus or idn"
+            )),
+        }
+    }
+}
+
+/// Steps applied to the SASL username and the sender before they are used as
+/// rate limit keys, so casing or sub-addresses can not split a quota.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Normalize {
+    pub steps: Vec<Step>,
+    /// Domain appended to SASL usernames without one.
+    pub default_domain: Option<String>,
+}
+
+impl Normalize {
+    fn has(&self, step: Step) -> bool {
+        self.steps.contains(&step)
+    }
+
+    /// Normalize an email address, or a bare login when `default_domain` is
+    /// `None`.
+    #[must_use]
+    pub fn address(&self, address: &str, default_domain: Option<&str>) -> String {
+        let address = if self.has(Step::Trim) {
+            address.trim()
+        } else {
+            address
+        };
+
+        let (local, domain) = match (address.rsplit_once('@'), default_domain) {
+            (Some((local, domain)), _) => (local, Some(domain)),
+            (None, Some(domain)) if !address.is_empty() => (address, Some(domain)),
+            (None, _) => (address, None),
+        };
+
+        let local = if self.has(Step::StripPlus) {
+            match local.split_once('+') {
+                Some((base, _)) if !base.is_empty() => base,
+                _ => local,
+            }
+        } else {
+            local
+        };
+
+        let domain = domain.map(|domain| {
+            if self.has(Step::Idn) {
+                idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_string())
+            } else {
+                domain.to_string()
+            }
+        });
+
+        let address = match domain {
+            Some(domain) => format!("{local}@{domain}"),
+            None => local.to_string(),
+        };
+
+        if self.has(Step::Lowercase) {
+            address.to_lowercase()
+        } else {
+            address
+        }
+    }
+
+    /// Normalize the SASL username and the sender of `request`.
+    pub fn apply(&self, request: &mut PolicyRequest) {
+        if *self == Self::default() {
+            return;
+        }
+
+        request.sasl_username = request
+            .sasl_username
+            .take()
+            .map(|username| self.address(&username, self.default_domain.as_deref()))
+            .filter(|username| !username.is_empty());
+        request.sender = request
+            .sender
+            .take()
+            .map(|sender| self.address(&sender, None))
+            .filter(|sender| !sender.is_empty());
+    }
+}
+
+/// Parse a comma separated list of steps, e.g. `trim,lowercase,strip-plus`.
+impl FromStr for Normalize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let steps = s
+            .split(',')
+            .map(str::trim)
+            .filter(|step| !step.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<Step>, _>>()?;
+
+        Ok(Self {
+            steps,
+            default_domain: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address() -> Result<(), String> {
+        let all: Normalize = "trim,lowercase,strip-plus,idn".parse()?;
+
+        assert_eq!(
+            all.address(" User+Tag@Example.COM ", None),
+            "user@example.com"
+        );
+        assert_eq!(
+            all.address("user@bücher.example", None),
+            "user@xn--bcher-kva.example"
+        );
+        assert_eq!(all.address("+tag@example.com", None), "+tag@example.com");
+        assert_eq!(
+            all.address("Login", Some("example.com")),
+            "login@example.com"
+        );
+        assert_eq!(all.address("login", None), "login");
+
+        // Nothing is changed unless asked for
+        let none = Normalize::default();
+        assert_eq!(
+            none.address("User+Tag@Example.COM", None),
+            "User+Tag@Example.COM"
+        );
+
+        assert!("uppercase".parse::<Normalize>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<(), String> {
+        let normalize = Normalize {
+            default_domain: Some("example.com".to_string()),
+            .."lowercase,strip-plus".parse()?
+        };
+        let mut request = PolicyRequest {
+            sasl_username: Some("Bob".to_string()),
+            sender: Some("Bob+news@Example.com".to_string()),
+            ..PolicyRequest::default()
+        };
+
+        normalize.apply(&mut request);
+
+        assert_eq!(request.sasl_username.as_deref(), Some("bob@example.com"));
+        // The default domain is only for bare SASL logins
+        assert_eq!(request.sender.as_deref(), Some("bob@example.com"));
+
+        Ok(())
+    }
+}
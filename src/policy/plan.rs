@@ -0,0 +1,149 @@
+use std::{fmt, str::FromStr};
+
+use regex::Regex;
+
+use crate::{
+    RateLimit,
+    policy::{PolicyAction, key::domain_of},
+};
+
+/// A named set of windows, e.g. a billing tier, used instead of the default
+/// windows for the users assigned to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Plan {
+    pub name: String,
+    pub windows: Vec<RateLimit>,
+    /// Action when a window of the plan is exceeded, `None` for the default
+    /// action.
+    pub action: Option<PolicyAction>,
+}
+
+/// Which users an [`Assignment`] applies to.
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    /// A single user, compared case-insensitively.
+    User(String),
+    /// Every user of a domain.
+    Domain(String),
+    /// Users matching a regular expression.
+    Regex(Regex),
+}
+
+impl Matcher {
+    #[must_use]
+    pub fn matches(&self, user: &str) -> bool {
+        match self {
+            Self::User(expected) => expected.eq_ignore_ascii_case(user),
+            Self::Domain(domain) => domain_of(user).as_deref() == Some(domain.as_str()),
+            Self::Regex(regex) => regex.is_match(user),
+        }
+    }
+}
+
+impl PartialEq for Matcher {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::User(a), Self::User(b)) | (Self::Domain(a), Self::Domain(b)) => a == b,
+            (Self::Regex(a), Self::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Matcher {}
+
+/// Parse `user@example.com`, `@example.com` for a whole domain or `~REGEX`.
+impl FromStr for Matcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Some(regex) = s.strip_prefix('~') {
+            return Regex::new(regex)
+                .map(Self::Regex)
+                .map_err(|e| format!("invalid regex {regex}: {e}"));
+        }
+
+        match s.strip_prefix("*@").or_else(|| s.strip_prefix('@')) {
+            Some(domain) if !domain.is_empty() => Ok(Self::Domain(domain.to_ascii_lowercase())),
+            Some(_) => Err(format!("invalid domain: {s}")),
+            None if s.is_empty() => Err("empty user".to_string()),
+            None => Ok(Self::User(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User(user) => f.write_str(user),
+            Self::Domain(domain) => write!(f, "@{domain}"),
+            Self::Regex(regex) => write!(f, "~{regex}"),
+        }
+    }
+}
+
+/// Users of a [`Plan`], given as `PATTERN=PLAN`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Assignment {
+    pub matcher: Matcher,
+    pub plan: String,
+}
+
+impl FromStr for Assignment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, plan) = s
+            .rsplit_once('=')
+            .filter(|(_, plan)| !plan.trim().is_empty())
+            .ok_or_else(|| format!("invalid assignment: {s}, expected PATTERN=PLAN"))?;
+
+        Ok(Self {
+            matcher: pattern.parse()?,
+            plan: plan.trim().to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matcher() -> Result<(), String> {
+        let user: Matcher = "VIP@example.com".parse()?;
+        assert!(user.matches("vip@example.com"));
+        assert!(!user.matches("other@example.com"));
+
+        for domain in ["@Example.com", "*@example.com"] {
+            let domain: Matcher = domain.parse()?;
+            assert_eq!(domain, Matcher::Domain("example.com".to_string()));
+            assert!(domain.matches("anyone@example.com"));
+            assert!(!domain.matches("anyone@example.net"));
+        }
+
+        let regex: Matcher = "~^newsletter-.*@".parse()?;
+        assert!(regex.matches("newsletter-weekly@example.com"));
+        assert!(!regex.matches("user@example.com"));
+
+        assert!("~(".parse::<Matcher>().is_err());
+        assert!("@".parse::<Matcher>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_assignment() {
+        assert_eq!(
+            "@example.com=business".parse(),
+            Ok(Assignment {
+                matcher: Matcher::Domain("example.com".to_string()),
+                plan: "business".to_string(),
+            })
+        );
+        assert!("@example.com".parse::<Assignment>().is_err());
+        assert!("@example.com=".parse::<Assignment>().is_err());
+    }
+}
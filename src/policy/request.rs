@@ -0,0 +1,384 @@
+use std::{collections::BTreeMap, fmt, net::IpAddr, str::FromStr};
+
+/// The only request type defined by the Postfix policy delegation protocol.
+pub const SMTPD_ACCESS_POLICY: &str = "smtpd_access_policy";
+
+/// SMTP protocol state in which Postfix consulted the policy service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProtocolState {
+    Connect,
+    Helo,
+    Ehlo,
+    Mail,
+    Rcpt,
+    Data,
+    EndOfMessage,
+    Vrfy,
+    Etrn,
+    Other(String),
+}
+
+impl FromStr for ProtocolState {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_uppercase().as_str() {
+            "CONNECT" => Self::Connect,
+            "HELO" => Self::Helo,
+            "EHLO" => Self::Ehlo,
+            "MAIL" => Self::Mail,
+            "RCPT" => Self::Rcpt,
+            "DATA" => Self::Data,
+            "END-OF-MESSAGE" => Self::EndOfMessage,
+            "VRFY" => Self::Vrfy,
+            "ETRN" => Self::Etrn,
+            _ => Self::Other(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for ProtocolState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Connect => "CONNECT",
+            Self::Helo => "HELO",
+            Self::Ehlo => "EHLO",
+            Self::Mail => "MAIL",
+            Self::Rcpt => "RCPT",
+            Self::Data => "DATA",
+            Self::EndOfMessage => "END-OF-MESSAGE",
+            Self::Vrfy => "VRFY",
+            Self::Etrn => "ETRN",
+            Self::Other(state) => state,
+        })
+    }
+}
+
+/// Errors produced while parsing a policy request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// A line that is not of the form `name=value`.
+    MalformedLine(String),
+    /// An attribute name with characters outside `[a-z0-9_]`.
+    InvalidName(String),
+    /// The same attribute was sent more than once.
+    DuplicateAttribute(String),
+    /// The mandatory `request` attribute is missing.
+    MissingRequest,
+    /// The `request` attribute is not `smtpd_access_policy`.
+    UnsupportedRequest(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedLine(line) => write!(f, "malformed line, expected name=value: {line:?}"),
+            Self::InvalidName(name) => write!(f, "invalid attribute name: {name:?}"),
+            Self::DuplicateAttribute(name) => write!(f, "duplicate attribute: {name}"),
+            Self::MissingRequest => write!(f, "missing request attribute"),
+            Self::UnsupportedRequest(request) => {
+                write!(f, "unsupported request type: {request:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A Postfix SMTP access policy request.
+///
+/// Empty attribute values (for example `sasl_username=` on unauthenticated
+/// sessions) are represented as `None`. Attributes this type does not know
+/// about, and typed ones with a value that does not parse, such as the
+/// `client_address=unknown` Postfix sends for unresolved clients, are kept
+/// verbatim in `extra`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PolicyRequest {
+    pub request: String,
+    pub protocol_state: Option<ProtocolState>,
+    pub protocol_name: Option<String>,
+    pub helo_name: Option<String>,
+    pub queue_id: Option<String>,
+    pub sender: Option<String>,
+    pub recipient: Option<String>,
+    pub recipient_count: Option<u32>,
+    pub client_address: Option<IpAddr>,
+    pub client_name: Option<String>,
+    pub client_port: Option<u16>,
+    pub reverse_client_name: Option<String>,
+    pub server_address: Option<IpAddr>,
+    pub server_port: Option<u16>,
+    pub instance: Option<String>,
+    pub sasl_method: Option<String>,
+    pub sasl_username: Option<String>,
+    pub sasl_sender: Option<String>,
+    pub size: Option<u64>,
+    pub ccert_subject: Option<String>,
+    pub ccert_issuer: Option<String>,
+    pub ccert_fingerprint: Option<String>,
+    pub ccert_pubkey_fingerprint: Option<String>,
+    pub encryption_protocol: Option<String>,
+    pub encryption_cipher: Option<String>,
+    pub encryption_keysize: Option<u32>,
+    pub etrn_domain: Option<String>,
+    pub stress: Option<String>,
+    pub policy_context: Option<String>,
+    pub compatibility_level: Option<String>,
+    pub mail_version: Option<String>,
+    pub extra: BTreeMap<String, String>,
+}
+
+fn text(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+impl PolicyRequest {
+    /// Parse a request from its attribute lines, without the terminating
+    /// empty line.
+    ///
+    /// # Errors
+    /// Returns an error if a line is malformed, an attribute is repeated or
+    /// the request type is missing or unsupported.
+    pub fn parse<'a, I>(lines: I) -> Result<Self, ParseError>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut request = Self::default();
+        let mut seen = Vec::new();
+
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(ParseError::MalformedLine(line.to_string()));
+            };
+
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(ParseError::MalformedLine(line.to_string()));
+            }
+            if !name
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+            {
+                return Err(ParseError::InvalidName(name.to_string()));
+            }
+            if seen.contains(&name) {
+                return Err(ParseError::DuplicateAttribute(name.to_string()));
+            }
+            seen.push(name);
+
+            request.set(name, value.trim());
+        }
+
+        if request.request.is_empty() {
+            return Err(ParseError::MissingRequest);
+        }
+        if request.request != SMTPD_ACCESS_POLICY {
+            return Err(ParseError::UnsupportedRequest(request.request));
+        }
+
+        Ok(request)
+    }
+
+    /// Value of a typed attribute, `None` when it is empty or does not parse,
+    /// e.g. `unknown`. Values that do not parse are kept in `extra`.
+    fn typed<T: FromStr>(&mut self, name: &str, value: &str) -> Option<T> {
+        if value.is_empty() {
+            return None;
+        }
+
+        let parsed = value.parse().ok();
+        if parsed.is_none() {
+            self.extra.insert(name.to_string(), value.to_string());
+        }
+
+        parsed
+    }
+
+    fn set(&mut self, name: &str, value: &str) {
+        match name {
+            "request" => value.clone_into(&mut self.request),
+            "protocol_state" => self.protocol_state = self.typed(name, value),
+            "protocol_name" => self.protocol_name = text(value),
+            "helo_name" => self.helo_name = text(value),
+            "queue_id" => self.queue_id = text(value),
+            "sender" => self.sender = text(value),
+            "recipient" => self.recipient = text(value),
+            "recipient_count" => self.recipient_count = self.typed(name, value),
+            "client_address" => self.client_address = self.typed(name, value),
+            "client_name" => self.client_name = text(value),
+            "client_port" => self.client_port = self.typed(name, value),
+            "reverse_client_name" => self.reverse_client_name = text(value),
+            "server_address" => self.server_address = self.typed(name, value),
+            "server_port" => self.server_port = self.typed(name, value),
+            "instance" => self.instance = text(value),
+            "sasl_method" => self.sasl_method = text(value),
+            "sasl_username" => self.sasl_username = text(value),
+            "sasl_sender" => self.sasl_sender = text(value),
+            "size" => self.size = self.typed(name, value),
+            "ccert_subject" => self.ccert_subject = text(value),
+            "ccert_issuer" => self.ccert_issuer = text(value),
+            "ccert_fingerprint" => self.ccert_fingerprint = text(value),
+            "ccert_pubkey_fingerprint" => self.ccert_pubkey_fingerprint = text(value),
+            "encryption_protocol" => self.encryption_protocol = text(value),
+            "encryption_cipher" => self.encryption_cipher = text(value),
+            "encryption_keysize" => self.encryption_keysize = self.typed(name, value),
+            "etrn_domain" => self.etrn_domain = text(value),
+            "stress" => self.stress = text(value),
+            "policy_context" => self.policy_context = text(value),
+            "compatibility_level" => self.compatibility_level = text(value),
+            "mail_version" => self.mail_version = text(value),
+            _ => {
+                self.extra.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+}
+
+impl FromStr for PolicyRequest {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s.lines())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const REQUEST: &str = "request=smtpd_access_policy
+protocol_state=RCPT
+protocol_name=ESMTP
+helo_name=mail.example.com
+queue_id=ABC123DEF456
+sender=user@example.com
+recipient=to@example.net
+recipient_count=0
+client_address=192.0.2.10
+client_name=mail.example.com
+reverse_client_name=mail.example.com
+instance=123.456.7
+sasl_method=PLAIN
+sasl_username=authenticated-user@example.com
+sasl_sender=
+size=12345
+ccert_subject=
+ccert_issuer=
+ccert_fingerprint=
+encryption_protocol=TLSv1.3
+encryption_cipher=TLS_AES_256_GCM_SHA384
+encryption_keysize=256
+etrn_domain=
+stress=
+client_port=52344
+";
+
+    #[test]
+    fn test_parse_full_request() -> Result<(), ParseError> {
+        let request: PolicyRequest = REQUEST.parse()?;
+
+        assert_eq!(request.request, SMTPD_ACCESS_POLICY);
+        assert_eq!(request.protocol_state, Some(ProtocolState::Rcpt));
+        assert_eq!(request.sender.as_deref(), Some("user@example.com"));
+        assert_eq!(request.recipient.as_deref(), Some("to@example.net"));
+        assert_eq!(request.recipient_count, Some(0));
+        assert_eq!(
+            request.client_address,
+            Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10)))
+        );
+        assert_eq!(request.client_port, Some(52344));
+        assert_eq!(
+            request.sasl_username.as_deref(),
+            Some("authenticated-user@example.com")
+        );
+        assert_eq!(request.sasl_sender, None);
+        assert_eq!(request.size, Some(12345));
+        assert_eq!(request.encryption_keysize, Some(256));
+        assert_eq!(request.ccert_subject, None);
+        assert!(request.extra.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_unknown_attributes_are_kept() -> Result<(), ParseError> {
+        let request =
+            PolicyRequest::parse(["request=smtpd_access_policy", "future_attribute=a=b"])?;
+
+        assert_eq!(
+            request.extra.get("future_attribute").map(String::as_str),
+            Some("a=b")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_unknown_values() -> Result<(), ParseError> {
+        let request = PolicyRequest::parse([
+            "request=smtpd_access_policy",
+            "sasl_username=user@example.com",
+            "client_address=unknown",
+            "client_port=unknown",
+            "size=big",
+        ])?;
+
+        assert_eq!(request.sasl_username.as_deref(), Some("user@example.com"));
+        assert_eq!(request.client_address, None);
+        assert_eq!(request.client_port, None);
+        assert_eq!(request.size, None);
+        assert_eq!(
+            request.extra.get("client_address").map(String::as_str),
+            Some("unknown")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_protocol_state() {
+        assert_eq!(
+            "END-OF-MESSAGE".parse::<ProtocolState>(),
+            Ok(ProtocolState::EndOfMessage)
+        );
+        assert_eq!(
+            "BDAT".parse::<ProtocolState>(),
+            Ok(ProtocolState::Other("BDAT".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            PolicyRequest::parse(["request=smtpd_access_policy", "garbage"]),
+            Err(ParseError::MalformedLine("garbage".to_string()))
+        );
+        assert_eq!(
+            PolicyRequest::parse(["request=smtpd_access_policy", "=value"]),
+            Err(ParseError::MalformedLine("=value".to_string()))
+        );
+        assert_eq!(
+            PolicyRequest::parse(["request=smtpd_access_policy", "Sender=a@b"]),
+            Err(ParseError::InvalidName("Sender".to_string()))
+        );
+        assert_eq!(
+            PolicyRequest::parse(["request=smtpd_access_policy", "sender=a@b", "sender=c@d"]),
+            Err(ParseError::DuplicateAttribute("sender".to_string()))
+        );
+        assert_eq!(
+            PolicyRequest::parse(["sasl_username=user"]),
+            Err(ParseError::MissingRequest)
+        );
+        assert_eq!(
+            PolicyRequest::parse(["request=junk"]),
+            Err(ParseError::UnsupportedRequest("junk".to_string()))
+        );
+    }
+}
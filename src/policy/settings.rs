@@ -0,0 +1,316 @@
+use std::{borrow::Cow, str::FromStr};
+
+use crate::{
+    RateLimit,
+    policy::{
+        AddressList, Assignment, Key, Normalize, Plan, PolicyAction, PolicyRequest, Scope, Template,
+    },
+};
+
+/// What a single policy request costs against the quota.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Charge {
+    /// Every request costs one, regardless of the number of recipients.
+    #[default]
+    Messages,
+    /// Every request costs `recipient_count`, which Postfix only sends in the
+    /// DATA and END-OF-MESSAGE states. Requests without it cost one.
+    Recipients,
+}
+
+impl Charge {
+    /// Units to charge for the request.
+    #[must_use]
+    pub fn cost(self, request: &PolicyRequest) -> i32 {
+        match self {
+            Self::Messages => 1,
+            Self::Recipients => request
+                .recipient_count
+                .filter(|count| *count > 0)
+                .map_or(1, |count| i32::try_from(count).unwrap_or(i32::MAX)),
+        }
+    }
+}
+
+impl FromStr for Charge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "messages" => Ok(Self::Messages),
+            "recipients" => Ok(Self::Recipients),
+            _ => Err(format!(
+                "invalid charge: {s}, expected messages or recipients"
+            )),
+        }
+    }
+}
+
+/// Rate limiting settings applied to every policy request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Settings {
+    /// Request attributes identifying who is rate limited.
+    pub key: Key,
+    /// Normalization of the SASL username and sender before they are used.
+    pub normalize: Normalize,
+    pub windows: Vec<RateLimit>,
+    /// Maximum distinct recipients per key and window.
+    pub distinct_recipients: Vec<RateLimit>,
+    /// Maximum distinct client addresses per SASL username and window.
+    pub distinct_clients: Vec<RateLimit>,
+    /// Only log SASL usernames over `distinct_clients` instead of rejecting.
+    pub distinct_clients_log_only: bool,
+    /// Windows shared by every mailbox of a domain.
+    pub domain_windows: Vec<RateLimit>,
+    /// Windows shared by every request the daemon sees.
+    pub global_windows: Vec<RateLimit>,
+    /// Windows shared by every recipient of a domain, checked in the RCPT state.
+    pub recipient_domain_windows: Vec<RateLimit>,
+    /// Count recipient domains separately for every key.
+    pub recipient_domain_per_key: bool,
+    /// Windows per client network for requests without SASL username.
+    pub client_windows: Vec<RateLimit>,
+    pub charge: Charge,
+    /// Action returned when a request is over quota.
+    pub action: PolicyAction,
+    /// Reply text sent along with `action`.
+    pub message: Template,
+    /// Only charge accepted requests, rejected ones only increase the
+    /// rejection counter.
+    pub accepted_only: bool,
+    /// Named sets of windows used instead of `windows` for their users.
+    pub plans: Vec<Plan>,
+    /// Users of the plans, the first matching assignment wins.
+    pub assignments: Vec<Assignment>,
+    /// Users, domains and client networks that are never rate limited.
+    pub allowlist: AddressList,
+    /// Users, domains and client networks always answered with `block_action`.
+    pub blocklist: AddressList,
+    pub block_action: PolicyAction,
+    /// Reply text sent along with `block_action`.
+    pub block_message: Template,
+}
+
+impl Settings {
+    /// Plan assigned to the user of the key, see [`Key::user`], so every
+    /// request sharing a counter gets the same plan.
+    #[must_use]
+    pub fn plan(&self, request: &PolicyRequest) -> Option<&Plan> {
+        let user = self.key.user(request)?;
+
+        let assignment = self
+            .assignments
+            .iter()
+            .find(|assignment| assignment.matcher.matches(&user))?;
+
+        self.plans.iter().find(|plan| plan.name == assignment.plan)
+    }
+
+    /// The same settings with the list files read again.
+    ///
+    /// # Errors
+    /// Returns an error if a list file can not be read or has an invalid entry.
+    pub fn reload_lists(&self) -> anyhow::Result<Self> {
+        Ok(Self {
+            allowlist: self.allowlist.reload()?,
+            blocklist: self.blocklist.reload()?,
+            ..self.clone()
+        })
+    }
+
+    /// Settings for `request`, with the key's windows and default action
+    /// taken from its plan when it has one. Plan windows only count
+    /// messages or recipients, `--bytes` and `--warn` apply to the default
+    /// windows only.
+    #[must_use]
+    pub fn for_request(&self, request: &PolicyRequest) -> Cow<'_, Self> {
+        let Some(plan) = self.plan(request) else {
+            return Cow::Borrowed(self);
+        };
+
+        Cow::Owned(Self {
+            windows: plan.windows.clone(),
+            action: plan.action.clone().unwrap_or_else(|| self.action.clone()),
+            plans: Vec::new(),
+            assignments: Vec::new(),
+            ..self.clone()
+        })
+    }
+
+    /// Windows of a scope, empty when the scope is not limited.
+    #[must_use]
+    pub fn scope_windows(&self, scope: Scope) -> &[RateLimit] {
+        match scope {
+            Scope::Key => &self.windows,
+            Scope::Domain => &self.domain_windows,
+            Scope::Global => &self.global_windows,
+            Scope::RecipientDomain => &self.recipient_domain_windows,
+            Scope::Client => &self.client_windows,
+        }
+    }
+
+    /// Configured window of `rate` seconds in `scope`.
+    #[must_use]
+    pub fn window(&self, scope: Scope, rate: i32) -> Option<&RateLimit> {
+        self.scope_windows(scope)
+            .iter()
+            .find(|window| window.rate == rate)
+    }
+
+    /// Action and reply text for an exceeded window of `rate` seconds, falling
+    /// back to the defaults when the window has none of its own.
+    #[must_use]
+    pub fn over_quota(&self, scope: Scope, rate: i32) -> (&PolicyAction, &Template) {
+        let window = self.window(scope, rate);
+
+        (
+            window
+                .and_then(|window| window.action.as_ref())
+                .unwrap_or(&self.action),
+            window
+                .and_then(|window| window.message.as_ref())
+                .unwrap_or(&self.message),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_charge_cost() {
+        let mut request = PolicyRequest::default();
+
+        assert_eq!(Charge::Messages.cost(&request), 1);
+        assert_eq!(Charge::Recipients.cost(&request), 1);
+
+        request.recipient_count = Some(0);
+        assert_eq!(Charge::Recipients.cost(&request), 1);
+
+        request.recipient_count = Some(500);
+        assert_eq!(Charge::Messages.cost(&request), 1);
+        assert_eq!(Charge::Recipients.cost(&request), 500);
+    }
+
+    #[test]
+    fn test_over_quota() {
+        let defer = PolicyAction::Reply {
+            code: 450,
+            status: Some("4.7.1".to_string()),
+        };
+        let settings = Settings {
+            windows: vec![
+                RateLimit {
+                    action: Some(defer.clone()),
+                    ..RateLimit::new(7, 3600)
+                },
+                RateLimit::new(100, 86400),
+            ],
+            ..Settings::default()
+        };
+
+        assert_eq!(
+            settings.over_quota(Scope::Key, 3600),
+            (&defer, &Template::default())
+        );
+        assert_eq!(
+            settings.over_quota(Scope::Key, 86400),
+            (&PolicyAction::Reject, &Template::default())
+        );
+        // Windows left in the database from an older configuration use the defaults.
+        assert_eq!(
+            settings.over_quota(Scope::Key, 60),
+            (&PolicyAction::Reject, &Template::default())
+        );
+    }
+
+    #[test]
+    fn test_for_request() -> Result<(), String> {
+        let settings = Settings {
+            windows: vec![RateLimit::new(100, 3600)],
+            plans: vec![Plan {
+                name: "business".to_string(),
+                windows: vec![RateLimit::new(1000, 3600)],
+                action: Some(PolicyAction::Defer),
+            }],
+            assignments: vec![
+                "vip@example.net=business".parse()?,
+                "@example.com=missing".parse()?,
+            ],
+            ..Settings::default()
+        };
+
+        let request = PolicyRequest {
+            sasl_username: Some("vip@example.net".to_string()),
+            ..PolicyRequest::default()
+        };
+        let planned = settings.for_request(&request);
+        assert_eq!(planned.windows, vec![RateLimit::new(1000, 3600)]);
+        assert_eq!(planned.action, PolicyAction::Defer);
+
+        // Users without a plan, or assigned to an unknown one, get the defaults
+        for user in ["other@example.net", "user@example.com"] {
+            let request = PolicyRequest {
+                sasl_username: Some(user.to_string()),
+                ..PolicyRequest::default()
+            };
+            assert_eq!(settings.for_request(&request).windows, settings.windows);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_follows_key() -> Result<(), String> {
+        let settings = Settings {
+            key: "sender".parse()?,
+            plans: vec![
+                Plan {
+                    name: "free".to_string(),
+                    windows: vec![RateLimit::new(10, 3600)],
+                    action: None,
+                },
+                Plan {
+                    name: "business".to_string(),
+                    windows: vec![RateLimit::new(1000, 3600)],
+                    action: None,
+                },
+            ],
+            assignments: vec![
+                "alice@example.com=business".parse()?,
+                "bob@example.com=free".parse()?,
+            ],
+            ..Settings::default()
+        };
+
+        // Both logins share the sender counter, the plan follows the sender
+        for user in ["alice@example.com", "bob@example.com"] {
+            let request = PolicyRequest {
+                sasl_username: Some(user.to_string()),
+                sender: Some("news@example.com".to_string()),
+                ..PolicyRequest::default()
+            };
+            assert!(settings.plan(&request).is_none());
+        }
+
+        let request = PolicyRequest {
+            sasl_username: Some("bob@example.com".to_string()),
+            sender: Some("alice@example.com".to_string()),
+            ..PolicyRequest::default()
+        };
+        assert_eq!(
+            settings.plan(&request).map(|plan| plan.name.as_str()),
+            Some("business")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_charge_from_str() {
+        assert_eq!("messages".parse(), Ok(Charge::Messages));
+        assert_eq!("recipients".parse(), Ok(Charge::Recipients));
+        assert!("bytes".parse::<Charge>().is_err());
+    }
+}
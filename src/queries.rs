@@ -1,13 +1,184 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use sqlx::AnyPool;
+use sqlx::{Any, AnyPool, Transaction};
+use tracing::debug;
 
 use crate::RateLimit;
+
+/// Attempts of a transaction aborted by a concurrent one before giving up.
+const TRANSACTION_ATTEMPTS: u64 = 3;
+
+/// Whether the database aborted a transaction in favor of a concurrent one:
+/// SQLSTATE `40001` for deadlocks on `MariaDB` (error 1213) and serialization
+/// failures, `40P01` for deadlocks on Postgres. Concurrent first requests of
+/// a key take conflicting gap locks on `InnoDB`.
+fn is_conflict(error: &sqlx::Error) -> bool {
+    error
+        .as_database_error()
+        .and_then(sqlx::error::DatabaseError::code)
+        .is_some_and(|code| matches!(code.as_ref(), "40001" | "40P01"))
+}
+
+/// Run the transaction `attempt` again while it is aborted by a concurrent
+/// one, nothing was written by the aborted attempts.
+async fn retry<T, F>(mut attempt: impl FnMut() -> F) -> sqlx::Result<T>
+where
+    F: Future<Output = sqlx::Result<T>>,
+{
+    let mut attempts = 1;
+
+    loop {
+        match attempt().await {
+            Err(e) if attempts < TRANSACTION_ATTEMPTS && is_conflict(&e) => {
+                debug!("Retrying transaction aborted by a concurrent one: {}", e);
+
+                tokio::time::sleep(Duration::from_millis(10 * attempts)).await;
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
 #[derive(Clone, Debug, sqlx::FromRow)]
 pub struct RateLimitWindow {
     pub rate: i32,
     pub quota: i32,
     pub used: i32,
+    pub bytes_quota: Option<i64>,
+    pub bytes_used: i64,
+    /// Unix timestamp of the last reset.
+    pub rdate: i64,
+    /// Requests rejected since the last reset.
+    pub rejected: i32,
+    /// Non-zero for windows set by an administrator, which keep their quota
+    /// when the configured windows change.
+    pub custom: i32,
+}
+
+impl RateLimitWindow {
+    /// Whether a request of `cost` units and `size` bytes fits in the window.
+    #[must_use]
+    pub fn allows(&self, cost: i32, size: i64) -> bool {
+        self.used.saturating_add(cost) <= self.quota
+            && self
+                .bytes_quota
+                .is_none_or(|quota| self.bytes_used.saturating_add(size) <= quota)
+    }
+
+    /// Highest usage of the message or byte quota in percent once a request of
+    /// `cost` units and `size` bytes is charged.
+    #[must_use]
+    pub fn usage_percent(&self, cost: i32, size: i64) -> i64 {
+        let percent = |used: i64, quota: i64| {
+            if quota > 0 {
+                used.saturating_mul(100) / quota
+            } else {
+                100
+            }
+        };
+
+        let messages = percent(
+            i64::from(self.used.saturating_add(cost)),
+            i64::from(self.quota),
+        );
+
+        self.bytes_quota.map_or(messages, |quota| {
+            messages.max(percent(self.bytes_used.saturating_add(size), quota))
+        })
+    }
+
+    /// Seconds from the Unix timestamp `now` until the window is reset.
+    #[must_use]
+    pub fn reset_in(&self, now: i64) -> i64 {
+        self.rdate
+            .saturating_add(i64::from(self.rate))
+            .saturating_sub(now)
+            .max(0)
+    }
+}
+
+/// Outcome of [`Queries::consume`].
+#[derive(Clone, Debug)]
+pub struct Consumption {
+    /// Windows as they were before the request was charged.
+    pub windows: Vec<RateLimitWindow>,
+    /// Whether the request fits in every window.
+    pub allowed: bool,
+}
+
+/// Distinct values (e.g. recipients) a key used within a window, see
+/// [`Queries::count_recipients`] and [`Queries::track_client`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DistinctWindow {
+    pub rate: i32,
+    pub quota: i32,
+    /// Distinct values seen in the window before this request.
+    pub used: i32,
+    /// Whether the value of this request was already seen in the window.
+    pub known: bool,
+    /// Unix timestamp of the oldest value in the window.
+    pub oldest: Option<i64>,
+}
+
+impl DistinctWindow {
+    /// Whether the value of this request fits in the window.
+    #[must_use]
+    pub const fn allows(&self) -> bool {
+        self.known || self.used < self.quota
+    }
+
+    /// Seconds from the Unix timestamp `now` until the oldest value leaves
+    /// the window.
+    #[must_use]
+    pub fn reset_in(&self, now: i64) -> i64 {
+        self.oldest.map_or(0, |oldest| {
+            oldest
+                .saturating_add(i64::from(self.rate))
+                .saturating_sub(now)
+                .max(0)
+        })
+    }
+}
+
+/// Table storing the values counted by a [`DistinctWindow`].
+#[derive(Clone, Copy)]
+struct DistinctTable {
+    table: &'static str,
+    column: &'static str,
+}
+
+const RECIPIENTS: DistinctTable = DistinctTable {
+    table: "ratelimit_recipient",
+    column: "recipient",
+};
+
+const CLIENTS: DistinctTable = DistinctTable {
+    table: "ratelimit_client",
+    column: "client_address",
+};
+
+/// When [`Queries::track_distinct`] records the value of a request.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Record {
+    /// Never, the caller records it once the request is accepted.
+    Never,
+    /// When it fits in every window.
+    Allowed,
+    /// Always, so the count keeps growing over the limit.
+    Always,
+}
+
+#[derive(sqlx::FromRow)]
+struct SeenValue {
+    value: String,
+    seen: i64,
+}
+
+/// Windows counted under one key, see [`Queries::consume_all`].
+#[derive(Clone, Copy, Debug)]
+pub struct Counter<'a> {
+    pub key: &'a str,
+    pub windows: &'a [RateLimit],
 }
 
 #[derive(Clone)]
@@ -42,18 +213,175 @@ impl Queries {
             .starts_with("sqlite")
     }
 
+    fn windows_query(&self, for_update: bool) -> &'static str {
+        match (self.is_postgres(), self.is_sqlite(), for_update) {
+            (true, _, false) => {
+                "SELECT rate, quota, used, bytes_quota, bytes_used, rejected, custom,
+                    CAST(EXTRACT(EPOCH FROM rdate::timestamptz) AS BIGINT) AS rdate
+                 FROM ratelimit WHERE username = $1 ORDER BY rate"
+            }
+            (true, _, true) => {
+                "SELECT rate, quota, used, bytes_quota, bytes_used, rejected, custom,
+                    CAST(EXTRACT(EPOCH FROM rdate::timestamptz) AS BIGINT) AS rdate
+                 FROM ratelimit WHERE username = $1 ORDER BY rate FOR UPDATE"
+            }
+            // SQLite has no row locks, the write transaction locks the database
+            (false, true, _) => {
+                "SELECT rate, quota, used, bytes_quota, bytes_used, rejected, custom,
+                    CAST(strftime('%s', rdate) AS INTEGER) AS rdate
+                 FROM ratelimit WHERE username = ? ORDER BY rate"
+            }
+            (false, false, false) => {
+                "SELECT rate, quota, used, bytes_quota, bytes_used, rejected, custom,
+                    CAST(UNIX_TIMESTAMP(rdate) AS SIGNED) AS rdate
+                 FROM ratelimit WHERE username = ? ORDER BY rate"
+            }
+            (false, false, true) => {
+                "SELECT rate, quota, used, bytes_quota, bytes_used, rejected, custom,
+                    CAST(UNIX_TIMESTAMP(rdate) AS SIGNED) AS rdate
+                 FROM ratelimit WHERE username = ? ORDER BY rate FOR UPDATE"
+            }
+        }
+    }
+
+    fn insert_missing_query(&self) -> &'static str {
+        if self.is_postgres() {
+            "INSERT INTO ratelimit (username, quota, rate, bytes_quota) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (username, rate) DO NOTHING"
+        } else if self.is_sqlite() {
+            "INSERT OR IGNORE INTO ratelimit (username, quota, rate, bytes_quota)
+             VALUES (?, ?, ?, ?)"
+        } else {
+            "INSERT IGNORE INTO ratelimit (username, quota, rate, bytes_quota) VALUES (?, ?, ?, ?)"
+        }
+    }
+
+    fn update_limits_query(&self) -> &'static str {
+        if self.is_postgres() {
+            "UPDATE ratelimit SET quota = $1, bytes_quota = $2
+             WHERE username = $3 AND rate = $4 AND custom = 0"
+        } else {
+            "UPDATE ratelimit SET quota = ?, bytes_quota = ?
+             WHERE username = ? AND rate = ? AND custom = 0"
+        }
+    }
+
+    fn delete_stale_query(&self) -> &'static str {
+        if self.is_postgres() {
+            "DELETE FROM ratelimit WHERE username = $1 AND rate = $2 AND custom = 0"
+        } else {
+            "DELETE FROM ratelimit WHERE username = ? AND rate = ? AND custom = 0"
+        }
+    }
+
+    fn set_override_query(&self) -> &'static str {
+        if self.is_postgres() {
+            "INSERT INTO ratelimit (username, quota, rate, bytes_quota, custom)
+             VALUES ($1, $2, $3, $4, 1)
+             ON CONFLICT (username, rate) DO UPDATE
+             SET quota = EXCLUDED.quota, bytes_quota = EXCLUDED.bytes_quota, custom = 1"
+        } else if self.is_sqlite() {
+            "INSERT INTO ratelimit (username, quota, rate, bytes_quota, custom)
+             VALUES (?, ?, ?, ?, 1)
+             ON CONFLICT (username, rate) DO UPDATE
+             SET quota = excluded.quota, bytes_quota = excluded.bytes_quota, custom = 1"
+        } else {
+            "INSERT INTO ratelimit (username, quota, rate, bytes_quota, custom)
+             VALUES (?, ?, ?, ?, 1)
+             ON DUPLICATE KEY UPDATE
+             quota = VALUES(quota), bytes_quota = VALUES(bytes_quota), custom = 1"
+        }
+    }
+
+    fn clear_override_query(&self) -> &'static str {
+        if self.is_postgres() {
+            "UPDATE ratelimit SET custom = 0 WHERE username = $1 AND rate = $2"
+        } else {
+            "UPDATE ratelimit SET custom = 0 WHERE username = ? AND rate = ?"
+        }
+    }
+
+    fn update_quota_query(&self) -> &'static str {
+        if self.is_postgres() {
+            "UPDATE ratelimit SET used = used + $1, bytes_used = bytes_used + $2 WHERE username = $3"
+        } else {
+            "UPDATE ratelimit SET used = used + ?, bytes_used = bytes_used + ? WHERE username = ?"
+        }
+    }
+
+    fn record_rejection_query(&self) -> &'static str {
+        if self.is_postgres() {
+            "UPDATE ratelimit SET rejected = rejected + 1 WHERE username = $1"
+        } else {
+            "UPDATE ratelimit SET rejected = rejected + 1 WHERE username = ?"
+        }
+    }
+
+    fn reset_query(&self) -> &'static str {
+        if self.is_postgres() {
+            "WITH now_val AS (
+                    SELECT NOW() AS now_time
+                )
+                UPDATE ratelimit
+                SET used = 0, bytes_used = 0, rejected = 0, rdate = (SELECT now_time FROM now_val)
+                WHERE username = $1
+                AND rate < EXTRACT(EPOCH FROM (SELECT now_time FROM now_val) - rdate)"
+        } else if self.is_sqlite() {
+            "UPDATE ratelimit
+                SET used = 0, bytes_used = 0, rejected = 0, rdate = CURRENT_TIMESTAMP
+                WHERE username = ?
+                AND rate < (strftime('%s','now') - strftime('%s', rdate))"
+        } else {
+            "UPDATE ratelimit
+                SET used = 0, bytes_used = 0, rejected = 0, rdate = NOW()
+                WHERE username = ?
+                AND rate < TIMESTAMPDIFF(SECOND, rdate, NOW())"
+        }
+    }
+
+    fn expire_seen_query(&self, table: &str) -> String {
+        if self.is_postgres() {
+            format!("DELETE FROM {table} WHERE username = $1 AND seen < $2")
+        } else {
+            format!("DELETE FROM {table} WHERE username = ? AND seen < ?")
+        }
+    }
+
+    fn seen_query(&self, table: &str, column: &str) -> String {
+        if self.is_postgres() {
+            format!("SELECT {column} AS value, seen FROM {table} WHERE username = $1 FOR UPDATE")
+        } else if self.is_sqlite() {
+            format!("SELECT {column} AS value, seen FROM {table} WHERE username = ?")
+        } else {
+            format!("SELECT {column} AS value, seen FROM {table} WHERE username = ? FOR UPDATE")
+        }
+    }
+
+    fn record_seen_query(&self, table: &str, column: &str) -> String {
+        if self.is_postgres() {
+            format!(
+                "INSERT INTO {table} (username, {column}, seen) VALUES ($1, $2, $3)
+                 ON CONFLICT (username, {column}) DO UPDATE SET seen = EXCLUDED.seen"
+            )
+        } else if self.is_sqlite() {
+            format!(
+                "INSERT INTO {table} (username, {column}, seen) VALUES (?, ?, ?)
+                 ON CONFLICT (username, {column}) DO UPDATE SET seen = excluded.seen"
+            )
+        } else {
+            format!(
+                "INSERT INTO {table} (username, {column}, seen) VALUES (?, ?, ?)
+                 ON DUPLICATE KEY UPDATE seen = VALUES(seen)"
+            )
+        }
+    }
+
     /// Fetch rate limit windows for a user.
     ///
     /// # Errors
     /// Returns an error if the database query fails.
     pub async fn get_windows(&self, username: &str) -> sqlx::Result<Vec<RateLimitWindow>> {
-        let query = if self.is_postgres() {
-            "SELECT rate, quota, used FROM ratelimit WHERE username = $1 ORDER BY rate"
-        } else {
-            "SELECT rate, quota, used FROM ratelimit WHERE username = ? ORDER BY rate"
-        };
-
-        sqlx::query_as(query)
+        sqlx::query_as(self.windows_query(false))
             .bind(username)
             .fetch_all(&*self.pool)
             .await
@@ -69,9 +397,7 @@ impl Queries {
             return Ok(None);
         }
 
-        Ok(Some(
-            windows.iter().all(|window| window.used < window.quota),
-        ))
+        Ok(Some(windows.iter().all(|window| window.allows(1, 0))))
     }
 
     /// Insert new user windows with the provided limits and rates.
@@ -80,9 +406,9 @@ impl Queries {
     /// Returns an error if the database insert fails.
     pub async fn create_user(&self, username: &str, windows: &[RateLimit]) -> sqlx::Result<()> {
         let query = if self.is_postgres() {
-            "INSERT INTO ratelimit (username, quota, rate) VALUES ($1, $2, $3)"
+            "INSERT INTO ratelimit (username, quota, rate, bytes_quota) VALUES ($1, $2, $3, $4)"
         } else {
-            "INSERT INTO ratelimit (username, quota, rate) VALUES (?, ?, ?)"
+            "INSERT INTO ratelimit (username, quota, rate, bytes_quota) VALUES (?, ?, ?, ?)"
         };
 
         let mut tx = self.pool.begin().await?;
@@ -91,6 +417,7 @@ impl Queries {
                 .bind(username)
                 .bind(window.limit)
                 .bind(window.rate)
+                .bind(window.bytes)
                 .execute(&mut *tx)
                 .await?;
         }
@@ -103,21 +430,13 @@ impl Queries {
     /// # Errors
     /// Returns an error if the database insert fails.
     pub async fn ensure_windows(&self, username: &str, windows: &[RateLimit]) -> sqlx::Result<()> {
-        let query = if self.is_postgres() {
-            "INSERT INTO ratelimit (username, quota, rate) VALUES ($1, $2, $3)
-             ON CONFLICT (username, rate) DO NOTHING"
-        } else if self.is_sqlite() {
-            "INSERT OR IGNORE INTO ratelimit (username, quota, rate) VALUES (?, ?, ?)"
-        } else {
-            "INSERT IGNORE INTO ratelimit (username, quota, rate) VALUES (?, ?, ?)"
-        };
-
         let mut tx = self.pool.begin().await?;
         for window in windows {
-            sqlx::query(query)
+            sqlx::query(self.insert_missing_query())
                 .bind(username)
                 .bind(window.limit)
                 .bind(window.rate)
+                .bind(window.bytes)
                 .execute(&mut *tx)
                 .await?;
         }
@@ -125,18 +444,57 @@ impl Queries {
         Ok(())
     }
 
-    /// Increment the usage counter for a user.
+    /// Set the quota of a user's window regardless of the configured windows,
+    /// creating the window when missing. Usage counters are kept.
+    ///
+    /// # Errors
+    /// Returns an error if the database statement fails.
+    pub async fn set_override(&self, username: &str, window: &RateLimit) -> sqlx::Result<()> {
+        sqlx::query(self.set_override_query())
+            .bind(username)
+            .bind(window.limit)
+            .bind(window.rate)
+            .bind(window.bytes)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Remove the override of a user's window of `rate` seconds, it follows
+    /// the configured windows again from the next request.
     ///
     /// # Errors
     /// Returns an error if the database update fails.
-    pub async fn update_quota(&self, username: &str) -> sqlx::Result<()> {
-        let query = if self.is_postgres() {
-            "UPDATE ratelimit SET used = used + 1 WHERE username = $1"
-        } else {
-            "UPDATE ratelimit SET used = used + 1 WHERE username = ?"
-        };
+    pub async fn clear_override(&self, username: &str, rate: i32) -> sqlx::Result<()> {
+        sqlx::query(self.clear_override_query())
+            .bind(username)
+            .bind(rate)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Increase the usage counters of every window for a user by `cost` units
+    /// and `size` bytes.
+    ///
+    /// # Errors
+    /// Returns an error if the database update fails.
+    pub async fn update_quota(&self, username: &str, cost: i32, size: i64) -> sqlx::Result<()> {
+        sqlx::query(self.update_quota_query())
+            .bind(cost)
+            .bind(size)
+            .bind(username)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
 
-        sqlx::query(query)
+    /// Increment the rejection counter of every window for a user.
+    ///
+    /// # Errors
+    /// Returns an error if the database update fails.
+    pub async fn record_rejection(&self, username: &str) -> sqlx::Result<()> {
+        sqlx::query(self.record_rejection_query())
             .bind(username)
             .execute(&*self.pool)
             .await?;
@@ -148,44 +506,354 @@ impl Queries {
     /// # Errors
     /// Returns an error if the database update fails.
     pub async fn reset_quotas_if_expired(&self, username: &str) -> sqlx::Result<bool> {
-        let rows_affected = if self.is_postgres() {
-            sqlx::query(
-                "WITH now_val AS (
-                        SELECT NOW() AS now_time
-                    )
-                    UPDATE ratelimit
-                    SET used = 0, rdate = (SELECT now_time FROM now_val)
-                    WHERE username = $1
-                    AND rate < EXTRACT(EPOCH FROM (SELECT now_time FROM now_val) - rdate)",
-            )
+        let rows_affected = sqlx::query(self.reset_query())
             .bind(username)
             .execute(&*self.pool)
             .await?
-            .rows_affected()
-        } else if self.is_sqlite() {
-            sqlx::query(
-                "UPDATE ratelimit
-                    SET used = 0, rdate = CURRENT_TIMESTAMP
-                    WHERE username = ?
-                    AND rate < (strftime('%s','now') - strftime('%s', rdate))",
-            )
-            .bind(username)
-            .execute(&*self.pool)
+            .rows_affected();
+
+        Ok(rows_affected > 0)
+    }
+
+    /// Reset expired windows, check the request against every window and
+    /// charge it, all in one transaction so concurrent requests for the same
+    /// user can not overshoot the quota. Missing windows are created first.
+    ///
+    /// The request is charged when it fits in every window, or always when
+    /// `charge_rejected` is set. Rejected requests increment the rejection
+    /// counter.
+    ///
+    /// # Errors
+    /// Returns an error if any of the database statements fails, nothing is
+    /// charged in that case.
+    pub async fn consume(
+        &self,
+        username: &str,
+        windows: &[RateLimit],
+        cost: i32,
+        size: i64,
+        charge_rejected: bool,
+    ) -> sqlx::Result<Consumption> {
+        let counter = Counter {
+            key: username,
+            windows,
+        };
+        let consumption = self
+            .consume_all(&[counter], cost, size, charge_rejected)
             .await?
-            .rows_affected()
-        } else {
-            sqlx::query(
-                "UPDATE ratelimit
-                    SET used = 0, rdate = NOW()
-                    WHERE username = ?
-                    AND rate < TIMESTAMPDIFF(SECOND, rdate, NOW())",
-            )
+            .into_iter()
+            .next()
+            .unwrap_or(Consumption {
+                windows: Vec::new(),
+                allowed: true,
+            });
+
+        Ok(consumption)
+    }
+
+    /// Like [`Queries::consume`] for several counters at once: the request is
+    /// allowed only when it fits in the windows of every counter, and all of
+    /// them are charged or none.
+    ///
+    /// Returns one [`Consumption`] per counter, in the order given.
+    ///
+    /// # Errors
+    /// Returns an error if any of the database statements fails, nothing is
+    /// charged in that case.
+    pub async fn consume_all(
+        &self,
+        counters: &[Counter<'_>],
+        cost: i32,
+        size: i64,
+        charge_rejected: bool,
+    ) -> sqlx::Result<Vec<Consumption>> {
+        retry(|| self.try_consume_all(counters, cost, size, charge_rejected)).await
+    }
+
+    async fn try_consume_all(
+        &self,
+        counters: &[Counter<'_>],
+        cost: i32,
+        size: i64,
+        charge_rejected: bool,
+    ) -> sqlx::Result<Vec<Consumption>> {
+        // Lock the rows in key order so transactions charging overlapping
+        // counters (e.g. two users of one domain) can not deadlock
+        let mut sorted: Vec<&Counter<'_>> = counters.iter().collect();
+        sorted.sort_by_key(|counter| counter.key);
+
+        let mut tx = self.pool.begin().await?;
+
+        let mut current = Vec::with_capacity(sorted.len());
+        for counter in &sorted {
+            current.push(self.lock_windows(&mut tx, counter).await?);
+        }
+
+        let allowed = current
+            .iter()
+            .flatten()
+            .all(|window| window.allows(cost, size));
+
+        for counter in &sorted {
+            if allowed || charge_rejected {
+                sqlx::query(self.update_quota_query())
+                    .bind(cost)
+                    .bind(size)
+                    .bind(counter.key)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            if !allowed {
+                sqlx::query(self.record_rejection_query())
+                    .bind(counter.key)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        let mut consumed: Vec<(&str, Consumption)> = sorted
+            .into_iter()
+            .zip(current)
+            .map(|(counter, windows)| {
+                let allowed = windows.iter().all(|window| window.allows(cost, size));
+                (counter.key, Consumption { windows, allowed })
+            })
+            .collect();
+
+        Ok(counters
+            .iter()
+            .filter_map(|counter| {
+                let pos = consumed.iter().position(|(key, _)| *key == counter.key)?;
+                Some(consumed.swap_remove(pos).1)
+            })
+            .collect())
+    }
+
+    /// Check a recipient against the distinct recipients `username` mailed
+    /// within each window, at the Unix timestamp `now`.
+    ///
+    /// The recipient is not recorded, see [`Queries::record_recipient`], so
+    /// recipients of rejected requests do not count towards the limit.
+    ///
+    /// # Errors
+    /// Returns an error if any of the database statements fails.
+    pub async fn count_recipients(
+        &self,
+        username: &str,
+        recipient: &str,
+        windows: &[RateLimit],
+        now: i64,
+    ) -> sqlx::Result<Vec<DistinctWindow>> {
+        self.track_distinct(RECIPIENTS, username, recipient, windows, now, Record::Never)
+            .await
+    }
+
+    /// Record that `username` mailed `recipient` at the Unix timestamp `now`.
+    ///
+    /// # Errors
+    /// Returns an error if the statement fails.
+    pub async fn record_recipient(
+        &self,
+        username: &str,
+        recipient: &str,
+        now: i64,
+    ) -> sqlx::Result<()> {
+        let DistinctTable { table, column } = RECIPIENTS;
+
+        sqlx::query(&self.record_seen_query(table, column))
             .bind(username)
+            .bind(recipient)
+            .bind(now)
             .execute(&*self.pool)
-            .await?
-            .rows_affected()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Check a client address against the distinct addresses the SASL user
+    /// `username` authenticated from within each window, at the Unix
+    /// timestamp `now`.
+    ///
+    /// The address is recorded when it fits in every window, or always when
+    /// `record_over` is set so the count keeps growing while the user is only
+    /// flagged.
+    ///
+    /// # Errors
+    /// Returns an error if any of the database statements fails.
+    pub async fn track_client(
+        &self,
+        username: &str,
+        client_address: &str,
+        windows: &[RateLimit],
+        now: i64,
+        record_over: bool,
+    ) -> sqlx::Result<Vec<DistinctWindow>> {
+        let record = if record_over {
+            Record::Always
+        } else {
+            Record::Allowed
         };
 
-        Ok(rows_affected > 0)
+        self.track_distinct(CLIENTS, username, client_address, windows, now, record)
+            .await
+    }
+
+    /// Count the distinct values stored for `username` in `distinct` within
+    /// each window, and record `value` as told by `record`.
+    async fn track_distinct(
+        &self,
+        distinct: DistinctTable,
+        username: &str,
+        value: &str,
+        windows: &[RateLimit],
+        now: i64,
+        record: Record,
+    ) -> sqlx::Result<Vec<DistinctWindow>> {
+        retry(|| self.try_track_distinct(distinct, username, value, windows, now, record)).await
+    }
+
+    async fn try_track_distinct(
+        &self,
+        distinct: DistinctTable,
+        username: &str,
+        value: &str,
+        windows: &[RateLimit],
+        now: i64,
+        record: Record,
+    ) -> sqlx::Result<Vec<DistinctWindow>> {
+        let longest = windows.iter().map(|window| window.rate).max().unwrap_or(0);
+
+        let mut tx = self.pool.begin().await?;
+
+        // Forget values older than the longest window, this also takes the
+        // database write lock on SQLite
+        let DistinctTable { table, column } = distinct;
+
+        sqlx::query(&self.expire_seen_query(table))
+            .bind(username)
+            .bind(now.saturating_sub(i64::from(longest)))
+            .execute(&mut *tx)
+            .await?;
+
+        let seen: Vec<SeenValue> = sqlx::query_as(&self.seen_query(table, column))
+            .bind(username)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        let counted: Vec<DistinctWindow> = windows
+            .iter()
+            .map(|window| {
+                let since = now.saturating_sub(i64::from(window.rate));
+                let in_window = || seen.iter().filter(move |row| row.seen >= since);
+
+                DistinctWindow {
+                    rate: window.rate,
+                    quota: window.limit,
+                    used: i32::try_from(in_window().count()).unwrap_or(i32::MAX),
+                    known: in_window().any(|row| row.value == value),
+                    oldest: in_window().map(|row| row.seen).min(),
+                }
+            })
+            .collect();
+
+        let allowed = counted.iter().all(DistinctWindow::allows);
+        if record == Record::Always || (record == Record::Allowed && allowed) {
+            sqlx::query(&self.record_seen_query(table, column))
+                .bind(username)
+                .bind(value)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(counted)
+    }
+
+    /// Reset the expired windows of a counter and lock them for the rest of
+    /// the transaction, creating the configured ones that are missing,
+    /// updating the limits of those that are not overridden and deleting
+    /// the ones no longer configured, e.g. after a user moved to a plan.
+    async fn lock_windows(
+        &self,
+        tx: &mut Transaction<'_, Any>,
+        counter: &Counter<'_>,
+    ) -> sqlx::Result<Vec<RateLimitWindow>> {
+        // Writing first also takes the database write lock on SQLite
+        sqlx::query(self.reset_query())
+            .bind(counter.key)
+            .execute(&mut **tx)
+            .await?;
+
+        let current: Vec<RateLimitWindow> = sqlx::query_as(self.windows_query(true))
+            .bind(counter.key)
+            .fetch_all(&mut **tx)
+            .await?;
+
+        let missing = counter
+            .windows
+            .iter()
+            .any(|window| !current.iter().any(|row| row.rate == window.rate));
+        let outdated: Vec<&RateLimit> = counter
+            .windows
+            .iter()
+            .filter(|window| {
+                current.iter().any(|row| {
+                    row.rate == window.rate
+                        && row.custom == 0
+                        && (row.quota != window.limit || row.bytes_quota != window.bytes)
+                })
+            })
+            .collect();
+        let stale: Vec<i32> = current
+            .iter()
+            .filter(|row| {
+                row.custom == 0 && !counter.windows.iter().any(|window| window.rate == row.rate)
+            })
+            .map(|row| row.rate)
+            .collect();
+        if !missing && outdated.is_empty() && stale.is_empty() {
+            return Ok(current);
+        }
+
+        // Only administrator set windows outlive the configuration
+        for rate in stale {
+            sqlx::query(self.delete_stale_query())
+                .bind(counter.key)
+                .bind(rate)
+                .execute(&mut **tx)
+                .await?;
+        }
+
+        if missing {
+            for window in counter.windows {
+                sqlx::query(self.insert_missing_query())
+                    .bind(counter.key)
+                    .bind(window.limit)
+                    .bind(window.rate)
+                    .bind(window.bytes)
+                    .execute(&mut **tx)
+                    .await?;
+            }
+        }
+
+        // Windows follow the configuration unless an administrator set them
+        for window in outdated {
+            sqlx::query(self.update_limits_query())
+                .bind(window.limit)
+                .bind(window.bytes)
+                .bind(counter.key)
+                .bind(window.rate)
+                .execute(&mut **tx)
+                .await?;
+        }
+
+        sqlx::query_as(self.windows_query(true))
+            .bind(counter.key)
+            .fetch_all(&mut **tx)
+            .await
     }
 }
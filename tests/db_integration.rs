@@ -1,4 +1,5 @@
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{Result, anyhow};
 use sqlx::{AnyPool, any::AnyPoolOptions};
@@ -7,7 +8,8 @@ use tokio::time::{Duration, sleep};
 
 use policyd_rate_limit::{
     RateLimit,
-    queries::{Queries, RateLimitWindow},
+    policy::{Key, PolicyRequest},
+    queries::{Counter, DistinctWindow, Queries, RateLimitWindow},
 };
 
 const POSTGRES_SCHEMA: &str = r"
@@ -17,8 +19,26 @@ CREATE TABLE IF NOT EXISTS ratelimit (
     used INTEGER NOT NULL DEFAULT 0,
     rate INTEGER NOT NULL DEFAULT 0,
     rdate TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    bytes_quota BIGINT DEFAULT NULL,
+    bytes_used BIGINT NOT NULL DEFAULT 0,
+    rejected INTEGER NOT NULL DEFAULT 0,
+    custom INTEGER NOT NULL DEFAULT 0,
     PRIMARY KEY (username, rate)
 );
+
+CREATE TABLE IF NOT EXISTS ratelimit_recipient (
+    username VARCHAR(128) NOT NULL,
+    recipient VARCHAR(255) NOT NULL,
+    seen BIGINT NOT NULL,
+    PRIMARY KEY (username, recipient)
+);
+
+CREATE TABLE IF NOT EXISTS ratelimit_client (
+    username VARCHAR(128) NOT NULL,
+    client_address VARCHAR(45) NOT NULL,
+    seen BIGINT NOT NULL,
+    PRIMARY KEY (username, client_address)
+);
 ";
 
 const MARIADB_SCHEMA: &str = r"
@@ -28,8 +48,26 @@ CREATE TABLE IF NOT EXISTS ratelimit (
     used INT UNSIGNED NOT NULL DEFAULT 0,
     rate INT UNSIGNED NOT NULL DEFAULT 0,
     rdate DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    bytes_quota BIGINT DEFAULT NULL,
+    bytes_used BIGINT NOT NULL DEFAULT 0,
+    rejected INT UNSIGNED NOT NULL DEFAULT 0,
+    custom INT UNSIGNED NOT NULL DEFAULT 0,
     PRIMARY KEY (username, rate)
 ) ENGINE=InnoDB;
+
+CREATE TABLE IF NOT EXISTS ratelimit_recipient (
+    username VARCHAR(128) NOT NULL,
+    recipient VARCHAR(255) NOT NULL,
+    seen BIGINT NOT NULL,
+    PRIMARY KEY (username, recipient)
+) ENGINE=InnoDB;
+
+CREATE TABLE IF NOT EXISTS ratelimit_client (
+    username VARCHAR(128) NOT NULL,
+    client_address VARCHAR(45) NOT NULL,
+    seen BIGINT NOT NULL,
+    PRIMARY KEY (username, client_address)
+) ENGINE=InnoDB;
 ";
 
 const SQLITE_SCHEMA: &str = r"
@@ -39,8 +77,26 @@ CREATE TABLE IF NOT EXISTS ratelimit (
     used INTEGER NOT NULL DEFAULT 0,
     rate INTEGER NOT NULL DEFAULT 0,
     rdate TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    bytes_quota BIGINT DEFAULT NULL,
+    bytes_used BIGINT NOT NULL DEFAULT 0,
+    rejected INTEGER NOT NULL DEFAULT 0,
+    custom INTEGER NOT NULL DEFAULT 0,
     PRIMARY KEY (username, rate)
 );
+
+CREATE TABLE IF NOT EXISTS ratelimit_recipient (
+    username VARCHAR(128) NOT NULL,
+    recipient VARCHAR(255) NOT NULL,
+    seen BIGINT NOT NULL,
+    PRIMARY KEY (username, recipient)
+);
+
+CREATE TABLE IF NOT EXISTS ratelimit_client (
+    username VARCHAR(128) NOT NULL,
+    client_address VARCHAR(45) NOT NULL,
+    seen BIGINT NOT NULL,
+    PRIMARY KEY (username, client_address)
+);
 ";
 
 fn configure_testcontainers_host() {
@@ -116,24 +172,21 @@ fn window_by_rate(windows: &[RateLimitWindow], rate: i32) -> Result<&RateLimitWi
         .ok_or_else(|| anyhow!("missing window for rate {rate}"))
 }
 
+fn unix_now() -> Result<i64> {
+    Ok(i64::try_from(
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    )?)
+}
+
 fn hourly_daily_windows() -> Vec<RateLimit> {
-    vec![
-        RateLimit {
-            limit: 7,
-            rate: 3600,
-        },
-        RateLimit {
-            limit: 100,
-            rate: 86400,
-        },
-    ]
+    vec![RateLimit::new(7, 3600), RateLimit::new(100, 86400)]
 }
 
 async fn exercise_missing_user(queries: &Queries) -> Result<()> {
     let missing = "missing@example.com";
 
     assert_eq!(queries.is_within_quota(missing).await?, None);
-    queries.update_quota(missing).await?;
+    queries.update_quota(missing, 1, 0).await?;
     assert_eq!(queries.is_within_quota(missing).await?, None);
     assert!(!queries.reset_quotas_if_expired(missing).await?);
 
@@ -142,17 +195,11 @@ async fn exercise_missing_user(queries: &Queries) -> Result<()> {
 
 async fn exercise_zero_limit(queries: &Queries) -> Result<()> {
     let zero_limit = "zero@example.com";
-    let zero_windows = vec![
-        RateLimit { limit: 0, rate: 1 },
-        RateLimit {
-            limit: 10,
-            rate: 3600,
-        },
-    ];
+    let zero_windows = vec![RateLimit::new(0, 1), RateLimit::new(10, 3600)];
 
     queries.create_user(zero_limit, &zero_windows).await?;
     assert_eq!(queries.is_within_quota(zero_limit).await?, Some(false));
-    queries.update_quota(zero_limit).await?;
+    queries.update_quota(zero_limit, 1, 0).await?;
     assert_eq!(queries.is_within_quota(zero_limit).await?, Some(false));
 
     Ok(())
@@ -167,7 +214,7 @@ async fn exercise_hourly_daily(queries: &Queries) -> Result<()> {
 
     // Hitting the hourly limit blocks mail even though the daily limit remains available.
     for _ in 0..7 {
-        queries.update_quota(hourly_daily).await?;
+        queries.update_quota(hourly_daily, 1, 0).await?;
     }
     assert_eq!(queries.is_within_quota(hourly_daily).await?, Some(false));
     assert!(!queries.reset_quotas_if_expired(hourly_daily).await?);
@@ -180,19 +227,21 @@ async fn exercise_hourly_daily(queries: &Queries) -> Result<()> {
     assert_eq!(daily.used, 7);
     assert_eq!(daily.quota, 100);
 
+    // Freshly created windows reset a full window length from now.
+    let now = unix_now()?;
+    assert!((3500..=3600).contains(&hourly.reset_in(now)));
+    assert!((86300..=86400).contains(&daily.reset_in(now)));
+
     Ok(())
 }
 
 async fn exercise_backfill(queries: &Queries) -> Result<()> {
     let backfill = "backfill@example.com";
-    let partial_windows = vec![RateLimit {
-        limit: 3,
-        rate: 3600,
-    }];
+    let partial_windows = vec![RateLimit::new(3, 3600)];
     let windows = hourly_daily_windows();
 
     queries.create_user(backfill, &partial_windows).await?;
-    queries.update_quota(backfill).await?;
+    queries.update_quota(backfill, 1, 0).await?;
     queries.ensure_windows(backfill, &windows).await?;
 
     let windows = queries.get_windows(backfill).await?;
@@ -206,6 +255,74 @@ async fn exercise_backfill(queries: &Queries) -> Result<()> {
     Ok(())
 }
 
+async fn exercise_recipient_cost(queries: &Queries) -> Result<()> {
+    let recipients = "recipients@example.com";
+    let windows = hourly_daily_windows();
+
+    queries.create_user(recipients, &windows).await?;
+    queries.update_quota(recipients, 5, 0).await?;
+    queries.update_quota(recipients, 1, 0).await?;
+
+    let windows = queries.get_windows(recipients).await?;
+    let hourly = window_by_rate(&windows, 3600)?;
+    let daily = window_by_rate(&windows, 86400)?;
+    assert_eq!(hourly.used, 6);
+    assert_eq!(daily.used, 6);
+
+    Ok(())
+}
+
+async fn exercise_bytes(queries: &Queries) -> Result<()> {
+    let bytes = "bytes@example.com";
+    let windows = vec![
+        RateLimit {
+            bytes: Some(1000),
+            ..RateLimit::new(100, 3600)
+        },
+        RateLimit::new(1000, 86400),
+    ];
+
+    queries.create_user(bytes, &windows).await?;
+    queries.update_quota(bytes, 1, 600).await?;
+
+    let windows = queries.get_windows(bytes).await?;
+    let hourly = window_by_rate(&windows, 3600)?;
+    let daily = window_by_rate(&windows, 86400)?;
+    assert_eq!(hourly.bytes_quota, Some(1000));
+    assert_eq!(hourly.bytes_used, 600);
+    assert_eq!(daily.bytes_quota, None);
+    assert_eq!(daily.bytes_used, 600);
+
+    // A few large messages exhaust the byte quota long before the message quota.
+    assert!(hourly.allows(1, 400));
+    assert!(!hourly.allows(1, 401));
+    assert!(daily.allows(1, i64::MAX));
+
+    // Usage reports whichever of the message or byte quota is closer to the limit.
+    assert_eq!(hourly.usage_percent(1, 200), 80);
+    assert_eq!(daily.usage_percent(1, 200), 0);
+
+    Ok(())
+}
+
+async fn exercise_rejections(queries: &Queries) -> Result<()> {
+    let rejections = "rejections@example.com";
+    let windows = hourly_daily_windows();
+
+    queries.create_user(rejections, &windows).await?;
+    queries.update_quota(rejections, 1, 0).await?;
+    queries.record_rejection(rejections).await?;
+    queries.record_rejection(rejections).await?;
+
+    let windows = queries.get_windows(rejections).await?;
+    for window in windows {
+        assert_eq!(window.used, 1);
+        assert_eq!(window.rejected, 2);
+    }
+
+    Ok(())
+}
+
 async fn exercise_concurrent(queries: &Queries) -> Result<()> {
     let concurrent = "concurrent@example.com";
     let windows = hourly_daily_windows();
@@ -215,7 +332,7 @@ async fn exercise_concurrent(queries: &Queries) -> Result<()> {
     for _ in 0..10 {
         let queries = queries.clone();
         let user = concurrent.to_string();
-        set.spawn(async move { queries.update_quota(&user).await });
+        set.spawn(async move { queries.update_quota(&user, 1, 0).await });
     }
     while let Some(result) = set.join_next().await {
         result??;
@@ -229,19 +346,70 @@ async fn exercise_concurrent(queries: &Queries) -> Result<()> {
     Ok(())
 }
 
+async fn exercise_consume(queries: &Queries) -> Result<()> {
+    let consume = "consume@example.com";
+    let windows = hourly_daily_windows();
+
+    // The first request creates the windows and is charged right away.
+    let first = queries.consume(consume, &windows, 1, 0, false).await?;
+    assert!(first.allowed);
+    assert!(first.windows.iter().all(|window| window.used == 0));
+
+    let mut allowed = 1;
+    for _ in 0..9 {
+        if queries
+            .consume(consume, &windows, 1, 0, false)
+            .await?
+            .allowed
+        {
+            allowed += 1;
+        }
+    }
+    assert_eq!(allowed, 7);
+
+    let windows = queries.get_windows(consume).await?;
+    let hourly = window_by_rate(&windows, 3600)?;
+    assert_eq!(hourly.used, 7);
+    assert_eq!(hourly.rejected, 3);
+
+    Ok(())
+}
+
+async fn exercise_concurrent_consume(queries: &Queries) -> Result<()> {
+    let burst = "burst@example.com";
+    let windows = hourly_daily_windows();
+
+    // A burst of concurrent requests must never overshoot the hourly quota of 7.
+    let mut set = tokio::task::JoinSet::new();
+    for _ in 0..20 {
+        let queries = queries.clone();
+        let windows = windows.clone();
+        set.spawn(async move { queries.consume(burst, &windows, 1, 0, true).await });
+    }
+
+    let mut allowed = 0;
+    while let Some(result) = set.join_next().await {
+        if result??.allowed {
+            allowed += 1;
+        }
+    }
+    assert_eq!(allowed, 7);
+
+    let windows = queries.get_windows(burst).await?;
+    let hourly = window_by_rate(&windows, 3600)?;
+    assert_eq!(hourly.used, 20);
+    assert_eq!(hourly.rejected, 13);
+
+    Ok(())
+}
+
 async fn exercise_daily_cap(queries: &Queries) -> Result<()> {
     let daily_cap = "daily-cap@example.com";
-    let daily_windows = vec![
-        RateLimit { limit: 2, rate: 1 },
-        RateLimit {
-            limit: 2,
-            rate: 86400,
-        },
-    ];
+    let daily_windows = vec![RateLimit::new(2, 1), RateLimit::new(2, 86400)];
 
     queries.create_user(daily_cap, &daily_windows).await?;
     for _ in 0..2 {
-        queries.update_quota(daily_cap).await?;
+        queries.update_quota(daily_cap, 1, 0).await?;
     }
     assert_eq!(queries.is_within_quota(daily_cap).await?, Some(false));
 
@@ -255,19 +423,237 @@ async fn exercise_daily_cap(queries: &Queries) -> Result<()> {
     let short = window_by_rate(&windows, 1)?;
     let daily = window_by_rate(&windows, 86400)?;
     assert_eq!(short.used, 0);
+    assert_eq!(short.rejected, 0);
     assert_eq!(daily.used, 2);
     assert_eq!(queries.is_within_quota(daily_cap).await?, Some(false));
 
     Ok(())
 }
 
-async fn exercise_queries(queries: &Queries) -> Result<()> {
+async fn exercise_shared_counter(queries: &Queries) -> Result<()> {
+    let users = vec![RateLimit::new(10, 3600)];
+    let domain = vec![RateLimit::new(3, 3600)];
+
+    // Every mailbox is under its own quota, the domain caps them together.
+    let mut allowed = Vec::new();
+    for user in ["a@tenant.example", "b@tenant.example", "c@tenant.example"] {
+        for _ in 0..2 {
+            let counters = [
+                Counter {
+                    key: user,
+                    windows: &users,
+                },
+                Counter {
+                    key: "domain:tenant.example",
+                    windows: &domain,
+                },
+            ];
+            let consumed = queries.consume_all(&counters, 1, 0, false).await?;
+            assert_eq!(consumed.len(), 2);
+            allowed.push(consumed.iter().all(|consumption| consumption.allowed));
+        }
+    }
+    assert_eq!(allowed, vec![true, true, true, false, false, false]);
+
+    // Rejected requests are charged to neither counter.
+    let windows = queries.get_windows("c@tenant.example").await?;
+    assert_eq!(window_by_rate(&windows, 3600)?.used, 0);
+    assert_eq!(window_by_rate(&windows, 3600)?.rejected, 2);
+    let windows = queries.get_windows("domain:tenant.example").await?;
+    assert_eq!(window_by_rate(&windows, 3600)?.used, 3);
+
+    Ok(())
+}
+
+async fn exercise_overrides(queries: &Queries) -> Result<()> {
+    let vip = "vip@example.com";
+    let regular = "regular@example.com";
+    let defaults = vec![RateLimit::new(100, 3600)];
+
+    queries.consume(regular, &defaults, 1, 0, false).await?;
+    queries.consume(vip, &defaults, 1, 0, false).await?;
+    queries
+        .set_override(vip, &RateLimit::new(1000, 3600))
+        .await?;
+
+    // New defaults apply to regular users but never clobber overrides.
+    let defaults = vec![RateLimit::new(50, 3600)];
+    let regular_windows = queries.consume(regular, &defaults, 1, 0, false).await?;
+    let vip_windows = queries.consume(vip, &defaults, 1, 0, false).await?;
+    assert_eq!(window_by_rate(&regular_windows.windows, 3600)?.quota, 50);
+    assert_eq!(window_by_rate(&regular_windows.windows, 3600)?.used, 1);
+    assert_eq!(window_by_rate(&vip_windows.windows, 3600)?.quota, 1000);
+    assert_eq!(window_by_rate(&vip_windows.windows, 3600)?.used, 1);
+
+    queries.clear_override(vip, 3600).await?;
+    let vip_windows = queries.consume(vip, &defaults, 1, 0, false).await?;
+    assert_eq!(window_by_rate(&vip_windows.windows, 3600)?.quota, 50);
+
+    Ok(())
+}
+
+async fn exercise_plan_change(queries: &Queries) -> Result<()> {
+    let user = "plan-change@example.com";
+    let defaults = vec![RateLimit::new(2, 86400)];
+    let plan = vec![RateLimit::new(1000, 3600)];
+
+    queries.consume(user, &defaults, 1, 0, false).await?;
+    queries
+        .set_override(user, &RateLimit::new(5000, 604_800))
+        .await?;
+
+    // The default daily window no longer applies once the user is on the plan
+    let mut allowed = Vec::new();
+    for _ in 0..5 {
+        allowed.push(queries.consume(user, &plan, 1, 0, false).await?.allowed);
+    }
+    assert_eq!(allowed, vec![true; 5]);
+
+    // Overrides are kept
+    let rates: Vec<i32> = queries
+        .get_windows(user)
+        .await?
+        .iter()
+        .map(|window| window.rate)
+        .collect();
+    assert_eq!(rates, vec![3600, 604_800]);
+
+    Ok(())
+}
+
+/// Upgrade step of the 1.2.0 migration notes for the windows `-l 100 -r 86400`.
+const MARK_CUSTOM: &str = "UPDATE ratelimit SET custom = 1
+WHERE NOT (rate = 86400 AND quota = 100) OR bytes_quota IS NOT NULL";
+
+async fn exercise_upgrade(pool: &AnyPool, queries: &Queries) -> Result<()> {
+    let tuned = "upgrade-tuned@example.com";
+    let regular = "upgrade-regular@example.com";
+
+    // Rows written before the custom column existed, one tuned by hand
+    sqlx::raw_sql(&format!(
+        "INSERT INTO ratelimit (username, quota, rate) VALUES ('{tuned}', 500, 86400), ('{regular}', 100, 86400)"
+    ))
+    .execute(pool)
+    .await?;
+    sqlx::raw_sql(MARK_CUSTOM).execute(pool).await?;
+
+    let defaults = vec![RateLimit::new(50, 86400)];
+    let tuned_windows = queries.consume(tuned, &defaults, 1, 0, false).await?;
+    let regular_windows = queries.consume(regular, &defaults, 1, 0, false).await?;
+    assert_eq!(window_by_rate(&tuned_windows.windows, 86400)?.quota, 500);
+    assert_eq!(window_by_rate(&regular_windows.windows, 86400)?.quota, 50);
+
+    Ok(())
+}
+
+async fn exercise_distinct_recipients(queries: &Queries) -> Result<()> {
+    let sender = "distinct@example.com";
+    let windows = vec![RateLimit::new(2, 3600)];
+    let now = unix_now()?;
+
+    let mut allowed = Vec::new();
+    for recipient in [
+        "a@example.net",
+        "b@example.net",
+        "a@example.net",
+        "c@example.net",
+    ] {
+        let distinct = queries
+            .count_recipients(sender, recipient, &windows, now)
+            .await?;
+        let fits = distinct.iter().all(DistinctWindow::allows);
+        if fits {
+            queries.record_recipient(sender, recipient, now).await?;
+        }
+        allowed.push(fits);
+    }
+    // Mailing a known recipient again is always allowed.
+    assert_eq!(allowed, vec![true, true, true, false]);
+
+    // Counting alone does not record the recipient.
+    let distinct = queries
+        .count_recipients(sender, "d@example.net", &windows, now)
+        .await?;
+    assert_eq!(distinct.first().map(|window| window.used), Some(2));
+
+    // Recipients older than the window no longer count.
+    let later = now + 3601;
+    let distinct = queries
+        .count_recipients(sender, "c@example.net", &windows, later)
+        .await?;
+    assert_eq!(
+        distinct,
+        vec![DistinctWindow {
+            rate: 3600,
+            quota: 2,
+            used: 0,
+            known: false,
+            oldest: None,
+        }]
+    );
+
+    Ok(())
+}
+
+async fn exercise_distinct_clients(queries: &Queries) -> Result<()> {
+    let shared = "shared@example.com";
+    let windows = vec![RateLimit::new(2, 3600)];
+    let now = unix_now()?;
+
+    // Flagged addresses are still recorded, the count keeps growing.
+    let mut counts = Vec::new();
+    for client in ["192.0.2.1", "192.0.2.2", "192.0.2.3", "192.0.2.4"] {
+        let distinct = queries
+            .track_client(shared, client, &windows, now, true)
+            .await?;
+        counts.push(distinct.first().map(|window| window.used));
+    }
+    assert_eq!(counts, vec![Some(0), Some(1), Some(2), Some(3)]);
+
+    Ok(())
+}
+
+async fn exercise_long_key(queries: &Queries) -> Result<()> {
+    // Senders longer than the username column still get their own counter
+    let key: Key = "sender".parse().map_err(|e: String| anyhow!(e))?;
+    let local = "x".repeat(200);
+    let windows = vec![RateLimit::new(1, 3600)];
+
+    for domain in ["one.example", "two.example"] {
+        let request = PolicyRequest {
+            sender: Some(format!("{local}@{domain}")),
+            ..PolicyRequest::default()
+        };
+        let long = key
+            .render(&request)
+            .ok_or_else(|| anyhow!("no key for {domain}"))?;
+
+        assert!(queries.consume(&long, &windows, 1, 0, false).await?.allowed);
+        assert!(!queries.consume(&long, &windows, 1, 0, false).await?.allowed);
+    }
+
+    Ok(())
+}
+
+async fn exercise_queries(pool: &AnyPool, queries: &Queries) -> Result<()> {
     exercise_missing_user(queries).await?;
     exercise_zero_limit(queries).await?;
     exercise_hourly_daily(queries).await?;
     exercise_backfill(queries).await?;
+    exercise_recipient_cost(queries).await?;
+    exercise_bytes(queries).await?;
+    exercise_rejections(queries).await?;
     exercise_concurrent(queries).await?;
+    exercise_consume(queries).await?;
+    exercise_concurrent_consume(queries).await?;
     exercise_daily_cap(queries).await?;
+    exercise_shared_counter(queries).await?;
+    exercise_overrides(queries).await?;
+    exercise_upgrade(pool, queries).await?;
+    exercise_plan_change(queries).await?;
+    exercise_distinct_recipients(queries).await?;
+    exercise_distinct_clients(queries).await?;
+    exercise_long_key(queries).await?;
 
     Ok(())
 }
@@ -277,10 +663,10 @@ async fn run_db_test(dsn: &str, schema: &str, max_connections: u32) -> Result<()
 
     let pool = connect_with_retry(dsn, max_connections).await?;
 
-    sqlx::query(schema).execute(&pool).await?;
+    sqlx::raw_sql(schema).execute(&pool).await?;
 
-    let queries = Queries::new(pool);
-    exercise_queries(&queries).await
+    let queries = Queries::new(pool.clone());
+    exercise_queries(&pool, &queries).await
 }
 
 #[tokio::test]
@@ -332,3 +718,24 @@ async fn sqlite_queries() -> Result<()> {
 
     run_db_test(dsn, SQLITE_SCHEMA, 1).await
 }
+
+#[tokio::test]
+async fn sqlite_file_concurrent_consume() -> Result<()> {
+    // Several connections to the same file exercise SQLite's write locking.
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let path = std::env::temp_dir().join(format!(
+        "policyd-rate-limit-consume-{}-{nanos}.db",
+        std::process::id()
+    ));
+    let dsn = format!("sqlite://{}?mode=rwc", path.display());
+
+    sqlx::any::install_default_drivers();
+    let pool = connect_with_retry(&dsn, 5).await?;
+    sqlx::raw_sql(SQLITE_SCHEMA).execute(&pool).await?;
+
+    let result = exercise_concurrent_consume(&Queries::new(pool)).await;
+
+    let _ = std::fs::remove_file(&path);
+
+    result
+}
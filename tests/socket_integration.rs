@@ -2,6 +2,7 @@
 
 use std::os::unix::net::UnixListener as StdUnixListener;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Result, anyhow};
@@ -10,12 +11,14 @@ use sqlx::SqlitePool;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::UnixStream,
+    task::JoinHandle,
     time::sleep,
 };
 
 use policyd_rate_limit::{
     RateLimit,
-    cli::actions::{self, Action},
+    cli::actions::{self, Action, Reload},
+    policy::{AddressList, Charge, Plan, PolicyAction, Settings},
 };
 const SQLITE_SCHEMA: &str = r"
 CREATE TABLE IF NOT EXISTS ratelimit (
@@ -24,8 +27,26 @@ CREATE TABLE IF NOT EXISTS ratelimit (
     used INTEGER NOT NULL DEFAULT 0,
     rate INTEGER NOT NULL DEFAULT 0,
     rdate TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    bytes_quota BIGINT DEFAULT NULL,
+    bytes_used BIGINT NOT NULL DEFAULT 0,
+    rejected INTEGER NOT NULL DEFAULT 0,
+    custom INTEGER NOT NULL DEFAULT 0,
     PRIMARY KEY (username, rate)
 );
+
+CREATE TABLE IF NOT EXISTS ratelimit_recipient (
+    username VARCHAR(128) NOT NULL,
+    recipient VARCHAR(255) NOT NULL,
+    seen BIGINT NOT NULL,
+    PRIMARY KEY (username, recipient)
+);
+
+CREATE TABLE IF NOT EXISTS ratelimit_client (
+    username VARCHAR(128) NOT NULL,
+    client_address VARCHAR(45) NOT NULL,
+    seen BIGINT NOT NULL,
+    PRIMARY KEY (username, client_address)
+);
 ";
 
 fn socket_tests_enabled() -> bool {
@@ -102,7 +123,7 @@ async fn setup_sqlite_db(path: &Path) -> Result<String> {
     }
     let dsn = format!("sqlite://{}?mode=rwc", path.display());
     let pool = SqlitePool::connect(&dsn).await?;
-    sqlx::query(SQLITE_SCHEMA).execute(&pool).await?;
+    sqlx::raw_sql(SQLITE_SCHEMA).execute(&pool).await?;
     pool.close().await;
     Ok(dsn)
 }
@@ -128,94 +149,1219 @@ async fn read_policy_response(stream: &mut UnixStream) -> Result<String> {
     Ok(String::from_utf8_lossy(&buffer).to_string())
 }
 
-#[tokio::test]
-async fn socket_creates_rows_for_new_user() -> Result<()> {
-    if !socket_tests_enabled() {
-        eprintln!("Skipping socket integration test; set RUN_SOCKET_TESTS=1 to run.");
-        return Ok(());
-    }
+struct Daemon {
+    handle: JoinHandle<Result<()>>,
+    socket_path: PathBuf,
+    db_path: PathBuf,
+    dsn: String,
+}
 
-    let db_path = unique_path("socket", ".db")?;
-    let socket_path = unique_socket_path()?;
-    if !socket_bind_supported(&socket_path)? {
-        eprintln!("Skipping socket integration test; unix sockets are not permitted.");
-        return Ok(());
+impl Daemon {
+    /// Start the daemon on a fresh socket and database file.
+    ///
+    /// Returns `None` when socket tests are disabled or not supported.
+    async fn start(prefix: &str, settings: Settings) -> Result<Option<Self>> {
+        Self::start_with_reload(prefix, settings, None).await
     }
-    // Use a file-backed SQLite DB so the daemon and test can share it.
-    let dsn = setup_sqlite_db(&db_path).await?;
 
-    let windows = vec![
-        RateLimit {
-            limit: 7,
-            rate: 3600,
-        },
-        RateLimit {
-            limit: 100,
-            rate: 86400,
-        },
-        RateLimit {
-            limit: 10000,
-            rate: 2_592_000,
-        },
-    ];
+    /// Start the daemon with `reload` called on SIGHUP.
+    async fn start_with_reload(
+        prefix: &str,
+        settings: Settings,
+        reload: Option<Reload>,
+    ) -> Result<Option<Self>> {
+        if !socket_tests_enabled() {
+            eprintln!("Skipping socket integration test; set RUN_SOCKET_TESTS=1 to run.");
+            return Ok(None);
+        }
 
-    let action = Action::Run {
-        socket: socket_path.clone(),
-        dsn: SecretString::from(dsn.clone()),
-        pool: 1,
-        windows,
-    };
+        let db_path = unique_path(prefix, ".db")?;
+        let socket_path = unique_socket_path()?;
+        if !socket_bind_supported(&socket_path)? {
+            eprintln!("Skipping socket integration test; unix sockets are not permitted.");
+            return Ok(None);
+        }
+        // Use a file-backed SQLite DB so the daemon and test can share it.
+        let dsn = setup_sqlite_db(&db_path).await?;
 
-    // Run the daemon in the background for the socket test.
-    let handle = tokio::spawn(async move { actions::run::handle(action).await });
+        let action = Action::Run {
+            socket: socket_path.clone(),
+            dsn: SecretString::from(dsn.clone()),
+            pool: 1,
+            idle_timeout: Duration::from_secs(5),
+            settings,
+            reload,
+        };
 
-    // Wait for the socket to be created before connecting.
-    for _ in 0..50 {
-        if socket_path.exists() {
-            break;
+        // Run the daemon in the background for the socket test.
+        let handle = tokio::spawn(async move { actions::run::handle(action).await });
+
+        // Wait for the socket to be created before connecting.
+        for _ in 0..50 {
+            if socket_path.exists() {
+                break;
+            }
+            if handle.is_finished() {
+                let result = handle.await;
+                return Err(anyhow!("daemon exited early: {result:?}"));
+            }
+            sleep(Duration::from_millis(100)).await;
         }
-        if handle.is_finished() {
-            let result = handle.await;
-            return Err(anyhow!("daemon exited early: {result:?}"));
+
+        if !socket_path.exists() {
+            if handle.is_finished() {
+                let result = handle.await;
+                return Err(anyhow!("daemon exited early: {result:?}"));
+            }
+            handle.abort();
+            let _ = handle.await;
+            return Err(anyhow!("socket was not created"));
         }
-        sleep(Duration::from_millis(100)).await;
+
+        Ok(Some(Self {
+            handle,
+            socket_path,
+            db_path,
+            dsn,
+        }))
     }
 
-    if !socket_path.exists() {
-        if handle.is_finished() {
-            let result = handle.await;
-            return Err(anyhow!("daemon exited early: {result:?}"));
-        }
-        handle.abort();
-        let _ = handle.await;
-        return Err(anyhow!("socket was not created"));
+    async fn stop(self) {
+        self.handle.abort();
+        let _ = self.handle.await;
+
+        let _ = std::fs::remove_file(&self.socket_path);
+        let _ = std::fs::remove_file(&self.db_path);
     }
+}
+
+#[tokio::test]
+async fn socket_creates_rows_for_new_user() -> Result<()> {
+    let windows = vec![
+        RateLimit::new(7, 3600),
+        RateLimit::new(100, 86400),
+        RateLimit::new(10000, 2_592_000),
+    ];
 
-    let mut stream = UnixStream::connect(&socket_path).await?;
+    let Some(daemon) = Daemon::start(
+        "socket",
+        Settings {
+            windows,
+            ..Settings::default()
+        },
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
     // Minimal policy request: sasl_username is the key used for rate limiting.
-    let payload = "request=smtpd\nsasl_username=socket-user@example.com\n\n";
+    let payload = "request=smtpd_access_policy\nsasl_username=socket-user@example.com\n\n";
     stream.write_all(payload.as_bytes()).await?;
 
     let response = read_policy_response(&mut stream).await?;
     if !response.contains("action=") {
-        handle.abort();
-        let _ = handle.await;
+        daemon.stop().await;
         return Err(anyhow!("unexpected policy response: {response}"));
     }
 
     // Verify one row per configured window for the new user.
-    let pool = SqlitePool::connect(&dsn).await?;
+    let pool = SqlitePool::connect(&daemon.dsn).await?;
     let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM ratelimit WHERE username = ?")
         .bind("socket-user@example.com")
         .fetch_one(&pool)
         .await?;
     assert_eq!(count.0, 3);
 
-    handle.abort();
-    let _ = handle.await;
+    daemon.stop().await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_serves_multiple_requests_per_connection() -> Result<()> {
+    let windows = vec![RateLimit::new(2, 3600)];
+
+    let Some(daemon) = Daemon::start(
+        "reuse",
+        Settings {
+            windows,
+            ..Settings::default()
+        },
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+
+    // Postfix reuses the connection, so every request is answered on the same stream.
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let payload = "request=smtpd_access_policy\nsasl_username=reuse-user@example.com\n\n";
+
+    let mut responses = Vec::new();
+    for _ in 0..4 {
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=REJECT sending limit exceeded, try again in 1 hour\n\n",
+            "action=REJECT sending limit exceeded, try again in 1 hour\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_charges_unknown_clients() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(1, 3600)],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("unknown-client", settings).await? else {
+        return Ok(());
+    };
+
+    // Postfix sends unknown for clients it could not resolve
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let payload = "request=smtpd_access_policy\nsasl_username=user@example.com\nclient_address=unknown\nclient_port=unknown\n\n";
+    let mut responses = Vec::new();
+    for _ in 0..2 {
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    assert_eq!(
+        responses.first().map(String::as_str),
+        Some("action=DUNNO\n\n")
+    );
+    assert!(
+        responses
+            .get(1)
+            .is_some_and(|response| response.starts_with("action=REJECT"))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_charges_recipient_count() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(10, 3600)],
+        charge: Charge::Recipients,
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("recipients", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+
+    let mut responses = Vec::new();
+    for recipient_count in [1, 8, 3] {
+        let payload = format!(
+            "request=smtpd_access_policy\nprotocol_state=END-OF-MESSAGE\n\
+             sasl_username=bulk-user@example.com\nrecipient_count={recipient_count}\n\n"
+        );
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    // 1 and 8 recipients fit, 3 more would exceed 10.
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=REJECT sending limit exceeded, try again in 1 hour\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_enforces_byte_quota() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit {
+            bytes: Some(1_000_000),
+            ..RateLimit::new(100, 3600)
+        }],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("bytes", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+
+    let mut responses = Vec::new();
+    for size in [1, 900_000, 200_000] {
+        let payload = format!(
+            "request=smtpd_access_policy\nprotocol_state=END-OF-MESSAGE\n\
+             sasl_username=upload-user@example.com\nsize={size}\n\n"
+        );
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    // 900000 bytes fit, 200000 more would exceed the 1000000 byte quota.
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=REJECT sending limit exceeded, try again in 1 hour\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_uses_configured_action_and_message() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(1, 3600)],
+        action: "450 4.7.1"
+            .parse::<PolicyAction>()
+            .map_err(|e| anyhow!(e))?,
+        message: "{user} reached {quota} messages per {window}s"
+            .parse()
+            .map_err(|e: String| anyhow!(e))?,
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("action", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let payload = "request=smtpd_access_policy\nsasl_username=hourly@example.com\n\n";
+
+    let mut responses = Vec::new();
+    for _ in 0..3 {
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    assert_eq!(
+        responses.last().map(String::as_str),
+        Some("action=450 4.7.1 hourly@example.com reached 1 messages per 3600s\n\n")
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_uses_action_of_exceeded_window() -> Result<()> {
+    let settings = Settings {
+        windows: vec![
+            RateLimit {
+                action: Some("450 4.7.1".parse().map_err(|e: String| anyhow!(e))?),
+                message: Some(
+                    "hourly limit reached"
+                        .parse()
+                        .map_err(|e: String| anyhow!(e))?,
+                ),
+                ..RateLimit::new(1, 3600)
+            },
+            RateLimit {
+                action: Some("550 5.7.1".parse().map_err(|e: String| anyhow!(e))?),
+                message: Some(
+                    "daily limit reached"
+                        .parse()
+                        .map_err(|e: String| anyhow!(e))?,
+                ),
+                ..RateLimit::new(2, 86400)
+            },
+        ],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("windows", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let payload = "request=smtpd_access_policy\nsasl_username=windows@example.com\n\n";
+
+    let mut responses = Vec::new();
+    for _ in 0..4 {
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    // The hourly window defers first, once the daily window is exceeded too it rejects.
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=450 4.7.1 hourly limit reached\n\n",
+            "action=550 5.7.1 daily limit reached\n\n",
+            "action=550 5.7.1 daily limit reached\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_warns_before_hard_limit() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit {
+            warn: Some(80),
+            ..RateLimit::new(5, 3600)
+        }],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("warn", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let payload = "request=smtpd_access_policy\nsasl_username=warn@example.com\n\n";
+
+    let mut responses = Vec::new();
+    for _ in 0..5 {
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    // The fourth message uses 4 of 5, which crosses the 80% threshold.
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=PREPEND X-RateLimit-Warning: 80% of the 1h sending limit used\n\n",
+            "action=PREPEND X-RateLimit-Warning: 100% of the 1h sending limit used\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_does_not_warn_at_end_of_message() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit {
+            warn: Some(50),
+            ..RateLimit::new(2, 3600)
+        }],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("warn-eom", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for state in ["END-OF-MESSAGE", "RCPT"] {
+        let payload = format!(
+            "request=smtpd_access_policy\nprotocol_state={state}\nsasl_username=eom@example.com\n\n"
+        );
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    // Postfix rejects PREPEND in smtpd_end_of_data_restrictions
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=PREPEND X-RateLimit-Warning: 100% of the 1h sending limit used\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_accepted_only_does_not_charge_rejections() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(1, 3600)],
+        accepted_only: true,
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("accepted", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let payload = "request=smtpd_access_policy\nsasl_username=retry@example.com\n\n";
+
+    for _ in 0..5 {
+        stream.write_all(payload.as_bytes()).await?;
+        read_policy_response(&mut stream).await?;
+    }
+
+    let pool = SqlitePool::connect(&daemon.dsn).await?;
+    let counters: (i64, i64) =
+        sqlx::query_as("SELECT used, rejected FROM ratelimit WHERE username = ?")
+            .bind("retry@example.com")
+            .fetch_one(&pool)
+            .await?;
+
+    daemon.stop().await;
+
+    // One accepted message, the four retries after it are only counted as rejections.
+    assert_eq!(counters, (1, 4));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_limits_by_sender_domain() -> Result<()> {
+    let settings = Settings {
+        key: "sender_domain".parse().map_err(|e: String| anyhow!(e))?,
+        windows: vec![RateLimit::new(2, 3600)],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("key", settings).await? else {
+        return Ok(());
+    };
+
+    // Different mailboxes of the same domain share one quota.
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for sender in ["a@example.com", "b@example.com", "c@Example.com", ""] {
+        let payload = format!("request=smtpd_access_policy\nsender={sender}\n\n");
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    let pool = SqlitePool::connect(&daemon.dsn).await?;
+    let used: (i64,) = sqlx::query_as("SELECT used FROM ratelimit WHERE username = ?")
+        .bind("example.com")
+        .fetch_one(&pool)
+        .await?;
+
+    daemon.stop().await;
+
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=REJECT sending limit exceeded, try again in 1 hour\n\n",
+            // The null sender has no domain and is never limited
+            "action=DUNNO\n\n",
+        ]
+    );
+    assert_eq!(used.0, 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_enforces_domain_windows() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(10, 3600)],
+        domain_windows: vec![RateLimit::new(3, 86400)],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("domain", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for user in [
+        "a@tenant.example",
+        "b@tenant.example",
+        "c@tenant.example",
+        "d@tenant.example",
+    ] {
+        let payload = format!("request=smtpd_access_policy\nsasl_username={user}\n\n");
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    // Other domains have their own counter.
+    let payload = "request=smtpd_access_policy\nsasl_username=a@other.example\n\n";
+    stream.write_all(payload.as_bytes()).await?;
+    responses.push(read_policy_response(&mut stream).await?);
+
+    daemon.stop().await;
+
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=REJECT sending limit exceeded, try again in 1 day\n\n",
+            "action=DUNNO\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_enforces_global_windows() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(10, 3600)],
+        global_windows: vec![RateLimit {
+            action: Some(PolicyAction::Defer),
+            ..RateLimit::new(2, 3600)
+        }],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("global", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for user in ["a@one.example", "b@two.example", "c@three.example"] {
+        let payload = format!("request=smtpd_access_policy\nsasl_username={user}\n\n");
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    // The global window has its own action, every other window keeps REJECT.
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=DEFER sending limit exceeded, try again in 1 hour\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_defers_busy_recipient_domains() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(100, 3600)],
+        recipient_domain_windows: vec![RateLimit {
+            action: Some(PolicyAction::Defer),
+            ..RateLimit::new(2, 3600)
+        }],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("rcpt", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for (user, recipient) in [
+        ("a@one.example", "x@gmail.com"),
+        ("b@two.example", "y@gmail.com"),
+        ("c@three.example", "z@gmail.com"),
+        ("c@three.example", "z@outlook.com"),
+    ] {
+        let payload = format!(
+            "request=smtpd_access_policy\nprotocol_state=RCPT\nsasl_username={user}\nrecipient={recipient}\n\n"
+        );
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=DEFER sending limit exceeded, try again in 1 hour\n\n",
+            "action=DUNNO\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_limits_distinct_recipients() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(100, 3600)],
+        distinct_recipients: vec![RateLimit::new(2, 3600)],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("distinct", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for recipient in [
+        "a@example.net",
+        "b@example.net",
+        "A@example.net",
+        "c@example.net",
+    ] {
+        let payload = format!(
+            "request=smtpd_access_policy\nprotocol_state=RCPT\nsasl_username=snowshoe@example.com\nrecipient={recipient}\n\n"
+        );
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=REJECT sending limit exceeded, try again in 1 hour\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_checks_distinct_recipients_in_rcpt_state_only() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(100, 3600)],
+        distinct_recipients: vec![RateLimit::new(1, 3600)],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("distinct-state", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for (state, recipient) in [
+        ("RCPT", "a@example.net"),
+        ("END-OF-MESSAGE", "b@example.net"),
+        ("END-OF-MESSAGE", "c@example.net"),
+        ("RCPT", "a@example.net"),
+        ("RCPT", "d@example.net"),
+    ] {
+        let payload = format!(
+            "request=smtpd_access_policy\nprotocol_state={state}\nsasl_username=eom@example.com\nrecipient={recipient}\n\n"
+        );
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    // END-OF-MESSAGE requests are neither checked nor recorded
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=REJECT sending limit exceeded, try again in 1 hour\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_does_not_record_recipients_over_quota() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(100, 3600)],
+        distinct_recipients: vec![RateLimit::new(2, 3600)],
+        recipient_domain_windows: vec![RateLimit {
+            action: Some(PolicyAction::Defer),
+            ..RateLimit::new(1, 3600)
+        }],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("distinct-quota", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for recipient in [
+        "x@example.org",
+        "y@example.org",
+        "a@example.net",
+        "b@example.net",
+    ] {
+        let payload = format!(
+            "request=smtpd_access_policy\nprotocol_state=RCPT\nsasl_username=quota@example.com\nrecipient={recipient}\n\n"
+        );
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    // y@example.org is deferred by the recipient domain window and does not
+    // count, so a@example.net is still the second distinct recipient
+    assert_eq!(
+        responses.first().map(String::as_str),
+        Some("action=DUNNO\n\n")
+    );
+    assert!(
+        responses
+            .get(1)
+            .is_some_and(|deferred| deferred.starts_with("action=DEFER"))
+    );
+    assert_eq!(
+        responses.get(2).map(String::as_str),
+        Some("action=DUNNO\n\n")
+    );
+    assert!(
+        responses
+            .get(3)
+            .is_some_and(|rejected| rejected.starts_with("action=REJECT"))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_rejects_shared_credentials() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(100, 3600)],
+        distinct_clients: vec![RateLimit::new(2, 3600)],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("clients", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for client in ["192.0.2.1", "198.51.100.7", "192.0.2.1", "203.0.113.9"] {
+        let payload = format!(
+            "request=smtpd_access_policy\nsasl_username=leaked@example.com\nclient_address={client}\n\n"
+        );
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=REJECT sending limit exceeded, try again in 1 hour\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_limits_unauthenticated_networks() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(100, 3600)],
+        client_windows: vec![RateLimit {
+            action: Some(PolicyAction::Defer),
+            ..RateLimit::new(2, 3600)
+        }],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("client", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for (user, client) in [
+        ("", "192.0.2.1"),
+        ("", "192.0.2.2"),
+        ("", "192.0.2.3"),
+        ("", "198.51.100.1"),
+        ("user@example.com", "192.0.2.4"),
+    ] {
+        let payload = format!(
+            "request=smtpd_access_policy\nsasl_username={user}\nclient_address={client}\n\n"
+        );
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    // The /24 shares one counter, authenticated users keep their own quota.
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=DEFER sending limit exceeded, try again in 1 hour\n\n",
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_uses_windows_of_assigned_plan() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(1, 3600)],
+        plans: vec![Plan {
+            name: "business".to_string(),
+            windows: vec![RateLimit::new(2, 3600)],
+            action: Some(PolicyAction::Defer),
+        }],
+        assignments: vec![
+            "@business.example=business"
+                .parse()
+                .map_err(anyhow::Error::msg)?,
+        ],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("plan", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for user in [
+        "free@example.com",
+        "free@example.com",
+        "paid@business.example",
+        "paid@business.example",
+        "paid@business.example",
+    ] {
+        let payload = format!("request=smtpd_access_policy\nsasl_username={user}\n\n");
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    assert_eq!(
+        responses,
+        vec![
+            "action=DUNNO\n\n",
+            "action=REJECT sending limit exceeded, try again in 1 hour\n\n",
+            "action=DUNNO\n\n",
+            "action=DUNNO\n\n",
+            "action=DEFER sending limit exceeded, try again in 1 hour\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_skips_allowlisted_senders() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(1, 3600)],
+        allowlist: AddressList::new(
+            vec![
+                "monitor@example.com".parse().map_err(anyhow::Error::msg)?,
+                "10.0.0.0/8".parse().map_err(anyhow::Error::msg)?,
+            ],
+            Vec::new(),
+        )?,
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("allowlist", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for (user, client) in [
+        ("monitor@example.com", "192.0.2.1"),
+        ("monitor@example.com", "192.0.2.1"),
+        ("relay@example.com", "10.1.2.3"),
+        ("relay@example.com", "10.1.2.3"),
+    ] {
+        let payload = format!(
+            "request=smtpd_access_policy\nsasl_username={user}\nclient_address={client}\n\n"
+        );
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    // Allowlisted requests never reach the database.
+    let pool = SqlitePool::connect(&daemon.dsn).await?;
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM ratelimit")
+        .fetch_one(&pool)
+        .await?;
+
+    daemon.stop().await;
+
+    assert_eq!(responses, vec!["action=DUNNO\n\n"; 4]);
+    assert_eq!(count.0, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_limits_forged_allowlisted_senders() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(1, 3600)],
+        client_windows: vec![RateLimit::new(1, 3600)],
+        allowlist: AddressList::new(
+            vec![
+                "monitor@example.com".parse().map_err(anyhow::Error::msg)?,
+                "@tickets.example".parse().map_err(anyhow::Error::msg)?,
+                "10.0.0.0/8".parse().map_err(anyhow::Error::msg)?,
+            ],
+            Vec::new(),
+        )?,
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("allowlist-sender", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for sender in [
+        "monitor@example.com",
+        "support@tickets.example",
+        "monitor@example.com",
+    ] {
+        let payload =
+            format!("request=smtpd_access_policy\nsasl_username=attacker@x\nsender={sender}\n\n");
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    // Without SASL username a forged sender is limited by its client
+    // network, only listed networks are exempt
+    for client in ["192.0.2.1", "192.0.2.1", "10.0.0.5", "10.0.0.5"] {
+        let payload = format!(
+            "request=smtpd_access_policy\nsender=monitor@example.com\nclient_address={client}\n\n"
+        );
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    assert_eq!(
+        responses.first().map(String::as_str),
+        Some("action=DUNNO\n\n")
+    );
+    assert!(
+        responses
+            .get(1..3)
+            .is_some_and(|rejected| rejected.iter().all(|r| r.starts_with("action=REJECT")))
+    );
+    assert_eq!(
+        responses.get(3).map(String::as_str),
+        Some("action=DUNNO\n\n")
+    );
+    assert!(
+        responses
+            .get(4)
+            .is_some_and(|forged| forged.starts_with("action=REJECT"))
+    );
+    assert!(
+        responses
+            .get(5..7)
+            .is_some_and(|relayed| relayed.iter().all(|r| r == "action=DUNNO\n\n"))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_rejects_blocklisted_senders() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(100, 3600)],
+        allowlist: AddressList::new(
+            vec!["@example.com".parse().map_err(anyhow::Error::msg)?],
+            Vec::new(),
+        )?,
+        blocklist: AddressList::new(
+            vec![
+                "compromised@example.com"
+                    .parse()
+                    .map_err(anyhow::Error::msg)?,
+            ],
+            Vec::new(),
+        )?,
+        block_action: PolicyAction::Reject,
+        block_message: "{user} is blocked".parse().map_err(anyhow::Error::msg)?,
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start("blocklist", settings).await? else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let mut responses = Vec::new();
+    for user in ["compromised@example.com", "user@example.com"] {
+        let payload = format!("request=smtpd_access_policy\nsasl_username={user}\n\n");
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    daemon.stop().await;
+
+    // The blocklist wins over the allowlist, without any quota evaluation.
+    assert_eq!(
+        responses,
+        vec![
+            "action=REJECT compromised@example.com is blocked\n\n",
+            "action=DUNNO\n\n",
+        ]
+    );
+
+    Ok(())
+}
+
+/// Settings returned by [`reload_settings`], `None` fails the reload.
+static RELOADED: Mutex<Option<Settings>> = Mutex::new(None);
+
+fn reload_settings() -> Result<Action> {
+    let settings = RELOADED
+        .lock()
+        .map_err(|_| anyhow!("poisoned"))?
+        .clone()
+        .ok_or_else(|| anyhow!("invalid configuration"))?;
+
+    Ok(Action::Run {
+        socket: PathBuf::new(),
+        dsn: SecretString::from(String::new()),
+        pool: 1,
+        idle_timeout: Duration::from_secs(5),
+        settings,
+        reload: None,
+    })
+}
+
+async fn hangup(settings: Option<Settings>) -> Result<()> {
+    *RELOADED.lock().map_err(|_| anyhow!("poisoned"))? = settings;
+
+    let status = std::process::Command::new("kill")
+        .args(["-HUP", &std::process::id().to_string()])
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("kill failed: {status}"));
+    }
+
+    sleep(Duration::from_millis(300)).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn socket_reloads_windows_on_hangup() -> Result<()> {
+    let settings = Settings {
+        windows: vec![RateLimit::new(1, 3600)],
+        ..Settings::default()
+    };
+
+    let Some(daemon) = Daemon::start_with_reload("reload", settings, Some(reload_settings)).await?
+    else {
+        return Ok(());
+    };
+
+    let mut stream = UnixStream::connect(&daemon.socket_path).await?;
+    let payload = "request=smtpd_access_policy\nsasl_username=user@example.com\n\n";
+    let mut responses = Vec::new();
+    for _ in 0..2 {
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    hangup(Some(Settings {
+        windows: vec![RateLimit::new(3, 3600)],
+        action: PolicyAction::Defer,
+        ..Settings::default()
+    }))
+    .await?;
+
+    // The connection survives the reload, the window allows one more request
+    for _ in 0..2 {
+        stream.write_all(payload.as_bytes()).await?;
+        responses.push(read_policy_response(&mut stream).await?);
+    }
+
+    // An invalid configuration keeps the current one
+    hangup(None).await?;
+    stream.write_all(payload.as_bytes()).await?;
+    responses.push(read_policy_response(&mut stream).await?);
+
+    let pool = SqlitePool::connect(&daemon.dsn).await?;
+    let quota: (i64,) = sqlx::query_as("SELECT quota FROM ratelimit WHERE username = ?")
+        .bind("user@example.com")
+        .fetch_one(&pool)
+        .await?;
+
+    daemon.stop().await;
 
-    let _ = std::fs::remove_file(&socket_path);
-    let _ = std::fs::remove_file(&db_path);
+    assert_eq!(
+        responses.first().map(String::as_str),
+        Some("action=DUNNO\n\n")
+    );
+    assert!(
+        responses
+            .get(1)
+            .is_some_and(|r| r.starts_with("action=REJECT"))
+    );
+    assert_eq!(
+        responses.get(2).map(String::as_str),
+        Some("action=DUNNO\n\n")
+    );
+    assert!(
+        responses
+            .get(3)
+            .is_some_and(|r| r.starts_with("action=DEFER"))
+    );
+    assert!(
+        responses
+            .get(4)
+            .is_some_and(|r| r.starts_with("action=DEFER"))
+    );
+    assert_eq!(quota.0, 3);
 
     Ok(())
 }